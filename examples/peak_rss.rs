@@ -0,0 +1,75 @@
+//! Compares the peak memory of a full-depth scan kept as `DiskItem`s and as an
+//! `ArenaTree`.
+//!
+//! Run each mode in its own process so peak RSS is not shared between them:
+//!
+//!     cargo run --release --example peak_rss -- disk-item /usr
+//!     cargo run --release --example peak_rss -- arena /usr
+
+use dirstat_rs::arena::ArenaTree;
+use dirstat_rs::{DiskItem, FileInfo, ScanOptions};
+use std::path::PathBuf;
+use std::time::Instant;
+
+fn count(item: &DiskItem) -> usize {
+    1 + item.children.iter().flatten().map(count).sum::<usize>()
+}
+
+#[cfg(unix)]
+fn peak_rss_kib() -> i64 {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // Safety: getrusage only writes into the provided struct
+    unsafe {
+        libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr());
+        usage.assume_init().ru_maxrss
+    }
+}
+
+#[cfg(not(unix))]
+fn peak_rss_kib() -> i64 {
+    -1
+}
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let (mode, path) = match (args.next(), args.next()) {
+        (Some(mode), Some(path)) => (mode, PathBuf::from(path)),
+        _ => {
+            eprintln!("usage: peak_rss <disk-item|arena> PATH");
+            std::process::exit(2);
+        }
+    };
+    let volume_id = match FileInfo::from_path(&path, false) {
+        Ok(FileInfo::Directory { volume_id, .. }) => volume_id,
+        _ => {
+            eprintln!("{} is not a directory", path.display());
+            std::process::exit(2);
+        }
+    };
+
+    let options = ScanOptions::default();
+    let start = Instant::now();
+    let nodes = match mode.as_str() {
+        "disk-item" => {
+            let tree = DiskItem::from_analyze_with(&path, false, volume_id, usize::MAX, &options)
+                .expect("scan failed");
+            count(&tree)
+        }
+        "arena" => {
+            let tree = ArenaTree::from_analyze_with(&path, false, volume_id, usize::MAX, &options)
+                .expect("scan failed");
+            tree.len()
+        }
+        _ => {
+            eprintln!("unknown mode {}", mode);
+            std::process::exit(2);
+        }
+    };
+    println!(
+        "{}: {} nodes in {:.2?}, peak RSS {} KiB",
+        mode,
+        nodes,
+        start.elapsed(),
+        peak_rss_kib()
+    );
+}
@@ -0,0 +1,48 @@
+//! Times repeated full-depth scans of a path and prints the fastest and median
+//! run, to compare walker changes against a previous build:
+//!
+//!     cargo run --release --example scan_time -- PATH [RUNS]
+
+use dirstat_rs::{DiskItem, FileInfo, ScanOptions};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let path = match args.next() {
+        Some(path) => PathBuf::from(path),
+        None => {
+            eprintln!("usage: scan_time PATH [RUNS]");
+            std::process::exit(2);
+        }
+    };
+    let runs = args
+        .next()
+        .map_or(10, |runs| runs.parse().expect("RUNS must be a number"));
+    let volume_id = match FileInfo::from_path(&path, false) {
+        Ok(FileInfo::Directory { volume_id, .. }) => volume_id,
+        _ => {
+            eprintln!("{} is not a directory", path.display());
+            std::process::exit(2);
+        }
+    };
+
+    let options = ScanOptions::default();
+    // Warm up the kernel caches so every run sees the same state
+    DiskItem::from_analyze_with(&path, false, volume_id, 0, &options).expect("scan failed");
+
+    let mut times = (0..runs)
+        .map(|_| {
+            let start = Instant::now();
+            DiskItem::from_analyze_with(&path, false, volume_id, 0, &options).expect("scan failed");
+            start.elapsed()
+        })
+        .collect::<Vec<Duration>>();
+    times.sort();
+    println!(
+        "{} runs: fastest {:.2?}, median {:.2?}",
+        runs,
+        times[0],
+        times[times.len() / 2]
+    );
+}
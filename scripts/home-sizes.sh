@@ -4,4 +4,4 @@
 [ -d /var/lib/prometheus/node-exporter ] || exit
 
 OUT_HOME_FILE=/var/lib/prometheus/node-exporter/home_sizes.prom
-home-sizes-prom -d 3 -t 30 -p 365 -c /var/lib/home-sizes/home.msgpack > "$OUT_HOME_FILE"
+home-sizes-prom --nice -d 3 -t 30 -p 365 -c /var/lib/home-sizes/home.msgpack --textfile "$OUT_HOME_FILE"
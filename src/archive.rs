@@ -0,0 +1,211 @@
+//! Members of archives, shown as the children of the archive in a tree.
+//!
+//! Archives are read in place, without extracting anything: the headers of a
+//! tar, decompressed on the fly for a .tar.gz, or the central directory of a
+//! zip. Members have their uncompressed size, while the archive itself still
+//! counts for its size on disk in the totals of its parents.
+
+use crate::{Node, ScanTree, SortBy};
+use flate2::read::GzDecoder;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Component, Path};
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+impl Format {
+    /// Format of an archive, from the extension of its name.
+    pub(crate) fn from_name(name: &OsStr) -> Option<Self> {
+        let name = name.to_string_lossy().to_lowercase();
+        if name.ends_with(".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(".zip") {
+            Some(Format::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// A member of an archive, or a directory implied by the paths of members.
+struct Member {
+    size: u64,
+    last_modified: SystemTime,
+    /// Members below a directory, `None` for files.
+    children: Option<BTreeMap<OsString, Member>>,
+}
+
+impl Member {
+    fn directory(last_modified: SystemTime) -> Self {
+        Member {
+            size: 0,
+            last_modified,
+            children: Some(BTreeMap::new()),
+        }
+    }
+
+    /// Uncompressed size and number of files of this member and those below it.
+    fn usage(&self) -> (u64, u64) {
+        match &self.children {
+            Some(children) => children
+                .values()
+                .map(Member::usage)
+                .fold((0, 0), |(size, count), (child_size, child_count)| {
+                    (size + child_size, count + child_count)
+                }),
+            None => (self.size, 1),
+        }
+    }
+
+    /// Adds a member at `path`, relative to this directory, along with the
+    /// directories leading to it. A later member replaces an earlier one of
+    /// the same path, as it would on extraction.
+    fn insert(&mut self, path: &Path, member: Member) {
+        let mut names = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name),
+                _ => None,
+            })
+            .peekable();
+        let mut directory = self;
+        while let Some(name) = names.next() {
+            let children = directory.children.get_or_insert_with(BTreeMap::new);
+            if names.peek().is_none() {
+                match children.get_mut(name) {
+                    // Entries for directories may come after their content
+                    Some(existing) if member.children.is_some() => {
+                        existing.last_modified = member.last_modified;
+                        existing.children.get_or_insert_with(BTreeMap::new);
+                    }
+                    _ => {
+                        children.insert(name.to_os_string(), member);
+                    }
+                }
+                return;
+            }
+            let last_modified = directory.last_modified;
+            directory = children
+                .entry(name.to_os_string())
+                .or_insert_with(|| Member::directory(last_modified));
+        }
+    }
+
+    fn into_tree<T: ScanTree>(
+        self,
+        store: &T::Store,
+        name: OsString,
+        depth_limit: usize,
+        sort: Option<SortBy>,
+    ) -> T {
+        let (size, file_count) = self.usage();
+        let node = Node {
+            name,
+            disk_size: size,
+            allocated_size: size,
+            file_count,
+            last_modified: self.last_modified,
+            incomplete: false,
+        };
+        let children = match self.children {
+            Some(children) if depth_limit > 0 => {
+                Some(into_trees(children, store, depth_limit - 1, sort))
+            }
+            _ => None,
+        };
+        T::from_node(store, node, children)
+    }
+}
+
+/// Reads the members of an archive, as the children of its tree item.
+/// Directories implied by member paths get the modification time of the
+/// archive, and so do all members of a zip.
+pub(crate) fn members<T: ScanTree>(
+    store: &T::Store,
+    format: Format,
+    file: File,
+    last_modified: SystemTime,
+    depth_limit: usize,
+    sort: Option<SortBy>,
+) -> io::Result<Vec<T>> {
+    let mut root = Member::directory(last_modified);
+    match format {
+        Format::Tar => read_tar(&mut root, tar::Archive::new(file).entries_with_seek()?)?,
+        Format::TarGz => {
+            let decoder = GzDecoder::new(BufReader::new(file));
+            read_tar(&mut root, tar::Archive::new(decoder).entries()?)?
+        }
+        Format::Zip => read_zip(&mut root, BufReader::new(file), last_modified)?,
+    }
+    let children = root.children.unwrap_or_default();
+    Ok(into_trees(children, store, depth_limit, sort))
+}
+
+fn into_trees<T: ScanTree>(
+    members: BTreeMap<OsString, Member>,
+    store: &T::Store,
+    depth_limit: usize,
+    sort: Option<SortBy>,
+) -> Vec<T> {
+    let mut items = members
+        .into_iter()
+        .map(|(name, member)| member.into_tree(store, name, depth_limit, sort))
+        .collect::<Vec<T>>();
+    if let Some(sort) = sort {
+        items.sort_unstable_by(|a, b| sort.compare_trees(a, b));
+    }
+    items
+}
+
+fn read_tar<R: io::Read>(root: &mut Member, entries: tar::Entries<R>) -> io::Result<()> {
+    for entry in entries {
+        let entry = entry?;
+        let header = entry.header();
+        let last_modified = header.mtime().map_or(root.last_modified, |secs| {
+            SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+        });
+        let member = if header.entry_type().is_dir() {
+            Member::directory(last_modified)
+        } else {
+            Member {
+                size: header.size()?,
+                last_modified,
+                children: None,
+            }
+        };
+        root.insert(&entry.path()?, member);
+    }
+    Ok(())
+}
+
+fn read_zip<R: io::Read + io::Seek>(
+    root: &mut Member,
+    reader: R,
+    last_modified: SystemTime,
+) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(reader)?;
+    for i in 0..archive.len() {
+        let file = archive.by_index_raw(i)?;
+        let member = if file.is_dir() {
+            Member::directory(last_modified)
+        } else {
+            Member {
+                size: file.size(),
+                last_modified,
+                children: None,
+            }
+        };
+        root.insert(Path::new(file.name()), member);
+    }
+    Ok(())
+}
@@ -0,0 +1,290 @@
+//! Compact tree representation for very large scans.
+//!
+//! An [`ArenaTree`] stores all entries in a handful of flat vectors instead of
+//! one allocation per entry: names are packed into a single byte buffer and
+//! nodes refer to their first child and next sibling by index. Directories are
+//! appended once their whole subtree is scanned, so nothing is copied while the
+//! tree grows.
+
+use crate::backend::RealFs;
+use crate::{analyze_root, AnalyzeConfig, DiskItem, FileIdMap, Node, ScanOptions, ScanTree, Usage};
+use std::convert::TryFrom;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+const NONE: u32 = u32::MAX;
+
+const HAS_CHILDREN: u8 = 1;
+const INCOMPLETE: u8 = 2;
+
+#[derive(Default)]
+pub struct ArenaTree {
+    /// Names of all nodes, as encoded `OsStr` bytes.
+    names: Vec<u8>,
+    /// End of each name in `names`, the start being the end of the previous one.
+    name_ends: Vec<u64>,
+    disk_sizes: Vec<u64>,
+    allocated_sizes: Vec<u64>,
+    file_counts: Vec<u64>,
+    /// Nanoseconds since the unix epoch.
+    last_modified: Vec<u64>,
+    first_children: Vec<u32>,
+    next_siblings: Vec<u32>,
+    flags: Vec<u8>,
+    root: u32,
+}
+
+impl ArenaTree {
+    /// Same as [`DiskItem::from_analyze_with`], building an arena.
+    pub fn from_analyze_with(
+        path: &Path,
+        apparent: bool,
+        root_dev: u64,
+        depth_limit: usize,
+        options: &ScanOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let config = AnalyzeConfig::without_cache(root_dev, apparent, options);
+        let store = Mutex::new(ArenaTree::default());
+        let root = options
+            .install(|| {
+                analyze_root::<Fragment, _>(
+                    &RealFs,
+                    path,
+                    &config,
+                    None,
+                    depth_limit,
+                    &FileIdMap::new(),
+                    &store,
+                )
+            })
+            .map_err(|e| -> Box<dyn Error> { e })?;
+
+        let mut tree = store.into_inner().unwrap();
+        tree.root = match root.index {
+            Some(index) => index,
+            None => tree.push(&root.node, NONE),
+        };
+        tree.shrink_to_fit();
+        Ok(tree)
+    }
+
+    pub fn root(&self) -> NodeRef<'_> {
+        NodeRef {
+            tree: self,
+            index: self.root as usize,
+        }
+    }
+
+    /// Number of nodes in the tree.
+    pub fn len(&self) -> usize {
+        self.flags.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flags.is_empty()
+    }
+
+    /// Bytes allocated for the tree.
+    pub fn heap_size(&self) -> usize {
+        self.names.capacity()
+            + 8 * (self.name_ends.capacity()
+                + self.disk_sizes.capacity()
+                + self.allocated_sizes.capacity()
+                + self.file_counts.capacity()
+                + self.last_modified.capacity())
+            + 4 * (self.first_children.capacity() + self.next_siblings.capacity())
+            + self.flags.capacity()
+    }
+
+    /// Appends a node whose children, if any, start at `first_child`.
+    fn push(&mut self, node: &Node, first_child: u32) -> u32 {
+        let index = u32::try_from(self.flags.len())
+            .ok()
+            .filter(|&index| index != NONE)
+            .expect("BUG: more than u32::MAX entries in a tree");
+        self.names.extend_from_slice(node.name.as_encoded_bytes());
+        self.name_ends.push(self.names.len() as u64);
+        self.disk_sizes.push(node.disk_size);
+        self.allocated_sizes.push(node.allocated_size);
+        self.file_counts.push(node.file_count);
+        self.last_modified.push(
+            node.last_modified
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos() as u64),
+        );
+        self.first_children.push(first_child);
+        self.next_siblings.push(NONE);
+        let mut flags = 0;
+        if first_child != NONE {
+            flags |= HAS_CHILDREN;
+        }
+        if node.incomplete {
+            flags |= INCOMPLETE;
+        }
+        self.flags.push(flags);
+        index
+    }
+
+    /// Appends a directory and those of its children not stored yet.
+    fn push_directory(&mut self, node: &Node, children: &[Fragment]) -> u32 {
+        let mut first_child = NONE;
+        let mut previous = NONE;
+        for child in children {
+            let index = match child.index {
+                Some(index) => index,
+                None => self.push(&child.node, NONE),
+            };
+            if previous == NONE {
+                first_child = index;
+            } else {
+                self.next_siblings[previous as usize] = index;
+            }
+            previous = index;
+        }
+        let index = self.push(node, first_child);
+        // An empty directory still keeps its (empty) list of children
+        self.flags[index as usize] |= HAS_CHILDREN;
+        index
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.names.shrink_to_fit();
+        self.name_ends.shrink_to_fit();
+        self.disk_sizes.shrink_to_fit();
+        self.allocated_sizes.shrink_to_fit();
+        self.file_counts.shrink_to_fit();
+        self.last_modified.shrink_to_fit();
+        self.first_children.shrink_to_fit();
+        self.next_siblings.shrink_to_fit();
+        self.flags.shrink_to_fit();
+    }
+}
+
+/// A node in an [`ArenaTree`].
+#[derive(Clone, Copy)]
+pub struct NodeRef<'a> {
+    tree: &'a ArenaTree,
+    index: usize,
+}
+
+impl<'a> NodeRef<'a> {
+    pub fn name(&self) -> &'a OsStr {
+        let start = match self.index {
+            0 => 0,
+            i => self.tree.name_ends[i - 1] as usize,
+        };
+        let end = self.tree.name_ends[self.index] as usize;
+        // Safety: the bytes were taken from an `OsStr` with `as_encoded_bytes`
+        unsafe { OsStr::from_encoded_bytes_unchecked(&self.tree.names[start..end]) }
+    }
+
+    pub fn disk_size(&self) -> u64 {
+        self.tree.disk_sizes[self.index]
+    }
+
+    pub fn allocated_size(&self) -> u64 {
+        self.tree.allocated_sizes[self.index]
+    }
+
+    pub fn file_count(&self) -> u64 {
+        self.tree.file_counts[self.index]
+    }
+
+    pub fn last_modified(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_nanos(self.tree.last_modified[self.index])
+    }
+
+    pub fn incomplete(&self) -> bool {
+        self.tree.flags[self.index] & INCOMPLETE != 0
+    }
+
+    /// Children of this node, or `None` if they were not kept at its depth.
+    pub fn children(&self) -> Option<Children<'a>> {
+        if self.tree.flags[self.index] & HAS_CHILDREN == 0 {
+            return None;
+        }
+        Some(Children {
+            tree: self.tree,
+            next: self.tree.first_children[self.index],
+        })
+    }
+
+    /// Copies this node and its subtree into a [`DiskItem`].
+    pub fn to_disk_item(&self) -> DiskItem {
+        DiskItem {
+            name: self.name().to_os_string(),
+            disk_size: self.disk_size(),
+            allocated_size: self.allocated_size(),
+            file_count: self.file_count(),
+            last_modified: self.last_modified(),
+            incomplete: self.incomplete(),
+            children: self
+                .children()
+                .map(|children| children.map(|child| child.to_disk_item()).collect()),
+        }
+    }
+}
+
+/// Iterator over the children of a [`NodeRef`].
+pub struct Children<'a> {
+    tree: &'a ArenaTree,
+    next: u32,
+}
+
+impl<'a> Iterator for Children<'a> {
+    type Item = NodeRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next == NONE {
+            return None;
+        }
+        let index = self.next as usize;
+        self.next = self.tree.next_siblings[index];
+        Some(NodeRef {
+            tree: self.tree,
+            index,
+        })
+    }
+}
+
+/// A scanned entry, before its parent is stored. Entries without kept
+/// children are stored along with their parent, in a single lock.
+pub(crate) struct Fragment {
+    node: Node,
+    index: Option<u32>,
+}
+
+impl ScanTree for Fragment {
+    type Store = Mutex<ArenaTree>;
+
+    fn from_node(store: &Self::Store, node: Node, children: Option<Vec<Self>>) -> Self {
+        let index = children.map(|children| {
+            let mut tree = store.lock().unwrap();
+            tree.push_directory(&node, &children)
+        });
+        Fragment { node, index }
+    }
+
+    fn name(&self) -> &OsStr {
+        &self.node.name
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            disk_size: self.node.disk_size,
+            allocated_size: self.node.allocated_size,
+            file_count: self.node.file_count,
+        }
+    }
+
+    fn last_modified(&self) -> SystemTime {
+        self.node.last_modified
+    }
+
+    fn incomplete(&self) -> bool {
+        self.node.incomplete
+    }
+}
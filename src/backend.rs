@@ -0,0 +1,460 @@
+//! Sources a scan can read from.
+//!
+//! A scan only lists directories and stats their entries, through an
+//! [`FsBackend`]. [`RealFs`] reads the actual filesystem, [`MemoryFs`] a tree
+//! built in memory, which makes scans reproducible in tests.
+//!
+//! On Linux a [`RealFs`] directory is an open file descriptor: entries are
+//! listed with `getdents64`, which also gives their type, and are stat'ed with
+//! `statx` relative to it, asking only for the fields the scan uses. Paths are
+//! never resolved again from the root. Elsewhere a directory is just its path.
+
+use crate::FileInfo;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+mod memory;
+
+pub use memory::MemoryFs;
+
+pub trait FsBackend: Sync {
+    /// An open directory.
+    type Dir: Sync;
+
+    /// Stats the root of a scan.
+    fn stat(&self, path: &Path, apparent: bool) -> io::Result<FileInfo>;
+
+    /// Opens the root of a scan.
+    fn open(&self, path: &Path) -> io::Result<Self::Dir>;
+
+    /// Opens a directory found in `parent`.
+    fn open_at(&self, parent: &Self::Dir, entry: &DirEntry) -> io::Result<Self::Dir>;
+
+    /// Lists the entries of a directory, which the scan does once per opened
+    /// directory. Entries that could not be read are counted as errors.
+    fn entries(&self, dir: &Self::Dir) -> io::Result<Vec<io::Result<DirEntry>>>;
+
+    /// Stats an entry of `parent`, without following symlinks.
+    fn stat_at(&self, parent: &Self::Dir, entry: &DirEntry, apparent: bool)
+        -> io::Result<FileInfo>;
+
+    /// Generation number of an open directory, which tells apart successive
+    /// directories that got the same file id. `None` if the filesystem has none.
+    fn generation(&self, _dir: &Self::Dir) -> Option<u64> {
+        None
+    }
+
+    /// Opens a file of `parent` for reading, to list the members of archives.
+    fn open_file(&self, _parent: &Self::Dir, _entry: &DirEntry) -> io::Result<File> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DirEntry {
+    pub name: OsString,
+    pub kind: EntryKind,
+}
+
+/// Type of an entry, as reported by the directory listing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EntryKind {
+    Directory,
+    File,
+    /// Sockets, pipes and device nodes, which take no space and are not stat'ed.
+    Special,
+    /// Not reported, the entry has to be stat'ed to know.
+    Unknown,
+}
+
+/// The filesystem of the machine.
+pub struct RealFs;
+
+/// A directory opened by [`RealFs`].
+pub struct RealDir(imp::Dir);
+
+impl FsBackend for RealFs {
+    type Dir = RealDir;
+
+    fn stat(&self, path: &Path, apparent: bool) -> io::Result<FileInfo> {
+        FileInfo::stat(path, apparent)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<RealDir> {
+        imp::Dir::open(path).map(RealDir)
+    }
+
+    fn open_at(&self, parent: &RealDir, entry: &DirEntry) -> io::Result<RealDir> {
+        parent.0.open_at(entry).map(RealDir)
+    }
+
+    fn entries(&self, dir: &RealDir) -> io::Result<Vec<io::Result<DirEntry>>> {
+        dir.0.entries()
+    }
+
+    fn stat_at(&self, parent: &RealDir, entry: &DirEntry, apparent: bool) -> io::Result<FileInfo> {
+        parent.0.stat_at(entry, apparent)
+    }
+
+    fn generation(&self, dir: &RealDir) -> Option<u64> {
+        dir.0.generation()
+    }
+
+    fn open_file(&self, parent: &RealDir, entry: &DirEntry) -> io::Result<File> {
+        parent.0.open_file(entry)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use super::{DirEntry, EntryKind};
+    use crate::FileInfo;
+    use std::ffi::{CStr, CString, OsStr, OsString};
+    use std::fs::File;
+    use std::io;
+    use std::mem::MaybeUninit;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::ffi::{OsStrExt, OsStringExt};
+    use std::path::Path;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::{Duration, SystemTime};
+
+    /// Set once `statx` turned out to be unavailable, e.g. on kernels older than
+    /// 4.11, or in seccomp sandboxes such as Docker or Flatpak which deny it
+    /// with `EPERM`.
+    static NO_STATX: AtomicBool = AtomicBool::new(false);
+
+    /// Size of the buffer given to `getdents64`.
+    const DIRENT_BUFFER: usize = 32 * 1024;
+
+    /// Longest name copied on the stack for system calls, NAME_MAX on Linux.
+    const NAME_MAX: usize = 255;
+
+    pub(super) struct Dir {
+        fd: OwnedFd,
+    }
+
+    impl Dir {
+        pub(super) fn open(path: &Path) -> io::Result<Self> {
+            let path = CString::new(path.as_os_str().as_bytes())?;
+            open_dir(libc::AT_FDCWD, &path)
+        }
+
+        pub(super) fn open_at(&self, entry: &DirEntry) -> io::Result<Self> {
+            with_c_name(&entry.name, |name| open_dir(self.fd.as_raw_fd(), name))
+        }
+
+        /// Lists the entries of the directory, which can only be done once.
+        pub(super) fn entries(&self) -> io::Result<Vec<io::Result<DirEntry>>> {
+            // u64 keeps the records aligned as the kernel expects
+            let mut buffer = vec![0u64; DIRENT_BUFFER / 8];
+            let mut entries = Vec::new();
+            loop {
+                // Safety: the kernel writes at most `DIRENT_BUFFER` bytes into the buffer
+                let read = unsafe {
+                    libc::syscall(
+                        libc::SYS_getdents64,
+                        self.fd.as_raw_fd(),
+                        buffer.as_mut_ptr(),
+                        DIRENT_BUFFER,
+                    )
+                };
+                if read < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if read == 0 {
+                    return Ok(entries);
+                }
+                // Safety: `read` bytes were just written, and u8 has no alignment requirement
+                let bytes = unsafe {
+                    std::slice::from_raw_parts(buffer.as_ptr().cast::<u8>(), read as usize)
+                };
+                let mut offset = 0;
+                while offset < bytes.len() {
+                    // struct linux_dirent64: u64 d_ino, i64 d_off, u16 d_reclen, u8 d_type, d_name
+                    let record_len =
+                        u16::from_ne_bytes([bytes[offset + 16], bytes[offset + 17]]) as usize;
+                    let d_type = bytes[offset + 18];
+                    let name = CStr::from_bytes_until_nul(&bytes[offset + 19..offset + record_len])
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    offset += record_len;
+                    if name.to_bytes() == b"." || name.to_bytes() == b".." {
+                        continue;
+                    }
+                    let kind = match d_type {
+                        libc::DT_DIR => EntryKind::Directory,
+                        libc::DT_REG | libc::DT_LNK => EntryKind::File,
+                        libc::DT_FIFO | libc::DT_SOCK | libc::DT_CHR | libc::DT_BLK => {
+                            EntryKind::Special
+                        }
+                        _ => EntryKind::Unknown,
+                    };
+                    entries.push(Ok(DirEntry {
+                        name: OsString::from_vec(name.to_bytes().to_vec()),
+                        kind,
+                    }));
+                }
+            }
+        }
+
+        pub(super) fn stat_at(&self, entry: &DirEntry, apparent: bool) -> io::Result<FileInfo> {
+            with_c_name(&entry.name, |name| {
+                if !NO_STATX.load(Ordering::Relaxed) {
+                    match statx(self.fd.as_raw_fd(), name, entry.kind, apparent) {
+                        Err(e) if matches!(e.raw_os_error(), Some(libc::ENOSYS | libc::EPERM)) => {
+                            NO_STATX.store(true, Ordering::Relaxed);
+                        }
+                        result => return result,
+                    }
+                }
+                fstatat(self.fd.as_raw_fd(), name, apparent)
+            })
+        }
+
+        /// Inode generation from `FS_IOC_GETVERSION`, which ext4 and a few
+        /// other filesystems support.
+        pub(super) fn generation(&self) -> Option<u64> {
+            // Some filesystems write an int and others a long, only equality
+            // of the bits matters
+            let mut generation: libc::c_long = 0;
+            // Safety: `generation` is large enough for either
+            let result = unsafe {
+                libc::ioctl(
+                    self.fd.as_raw_fd(),
+                    libc::FS_IOC_GETVERSION,
+                    &mut generation as *mut libc::c_long,
+                )
+            };
+            (result == 0).then_some(generation as u64)
+        }
+
+        /// Opens a regular file. A FIFO or a device may have replaced the
+        /// entry since it was listed: it is opened without blocking and
+        /// rejected.
+        pub(super) fn open_file(&self, entry: &DirEntry) -> io::Result<File> {
+            with_c_name(&entry.name, |name| {
+                let flags = libc::O_RDONLY | libc::O_NOFOLLOW | libc::O_NONBLOCK | libc::O_CLOEXEC;
+                // Safety: `name` is a valid C string
+                let fd = unsafe { libc::openat(self.fd.as_raw_fd(), name.as_ptr(), flags) };
+                if fd < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Safety: `fd` was just opened and is owned by nobody else
+                let file = File::from(unsafe { OwnedFd::from_raw_fd(fd) });
+                if !file.metadata()?.is_file() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "not a regular file",
+                    ));
+                }
+                Ok(file)
+            })
+        }
+    }
+
+    /// Calls `f` with `name` as a C string, without allocating for usual names.
+    fn with_c_name<R>(name: &OsStr, f: impl FnOnce(&CStr) -> io::Result<R>) -> io::Result<R> {
+        let bytes = name.as_bytes();
+        if bytes.len() > NAME_MAX {
+            return f(&CString::new(bytes)?);
+        }
+        let mut buffer = [0u8; NAME_MAX + 1];
+        buffer[..bytes.len()].copy_from_slice(bytes);
+        let name = CStr::from_bytes_with_nul(&buffer[..=bytes.len()])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        f(name)
+    }
+
+    fn open_dir(parent: RawFd, name: &CStr) -> io::Result<Dir> {
+        let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+        // Safety: `name` is a valid C string
+        let fd = unsafe { libc::openat(parent, name.as_ptr(), flags) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: `fd` was just opened and is owned by nobody else
+        Ok(Dir {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        })
+    }
+
+    fn statx(parent: RawFd, name: &CStr, kind: EntryKind, apparent: bool) -> io::Result<FileInfo> {
+        let directory_mask =
+            libc::STATX_TYPE | libc::STATX_INO | libc::STATX_MTIME | libc::STATX_CTIME;
+        let file_mask = directory_mask | libc::STATX_SIZE | libc::STATX_BLOCKS | libc::STATX_NLINK;
+        let mask = match kind {
+            EntryKind::Directory => directory_mask,
+            _ => file_mask,
+        };
+        let mut stx = MaybeUninit::<libc::statx>::zeroed();
+        // Safety: `name` is a valid C string and `stx` is large enough
+        let result = unsafe {
+            libc::statx(
+                parent,
+                name.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+                mask,
+                stx.as_mut_ptr(),
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: initialized by statx, or zeroed for fields it did not fill
+        let stx = unsafe { stx.assume_init() };
+        let volume_id = libc::makedev(stx.stx_dev_major, stx.stx_dev_minor);
+        let last_modified = system_time(stx.stx_mtime.tv_sec, stx.stx_mtime.tv_nsec as i64);
+        let changed = system_time(stx.stx_ctime.tv_sec, stx.stx_ctime.tv_nsec as i64);
+        Ok(file_info(
+            u32::from(stx.stx_mode) & libc::S_IFMT == libc::S_IFDIR,
+            volume_id,
+            stx.stx_ino,
+            u64::from(stx.stx_nlink),
+            last_modified,
+            changed,
+            stx.stx_size,
+            stx.stx_blocks,
+            apparent,
+        ))
+    }
+
+    // `st_nlink` is not u64 on every target
+    #[allow(clippy::unnecessary_cast)]
+    fn fstatat(parent: RawFd, name: &CStr, apparent: bool) -> io::Result<FileInfo> {
+        let mut st = MaybeUninit::<libc::stat>::zeroed();
+        // Safety: `name` is a valid C string and `st` is large enough
+        let result = unsafe {
+            libc::fstatat(
+                parent,
+                name.as_ptr(),
+                st.as_mut_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        };
+        if result < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Safety: initialized by fstatat
+        let st = unsafe { st.assume_init() };
+        Ok(file_info(
+            st.st_mode & libc::S_IFMT == libc::S_IFDIR,
+            st.st_dev,
+            st.st_ino,
+            st.st_nlink as u64,
+            system_time(st.st_mtime, st.st_mtime_nsec),
+            system_time(st.st_ctime, st.st_ctime_nsec),
+            st.st_size as u64,
+            st.st_blocks as u64,
+            apparent,
+        ))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn file_info(
+        is_dir: bool,
+        volume_id: u64,
+        file_id: u64,
+        links: u64,
+        last_modified: SystemTime,
+        changed: SystemTime,
+        size: u64,
+        blocks: u64,
+        apparent: bool,
+    ) -> FileInfo {
+        if is_dir {
+            FileInfo::Directory {
+                volume_id,
+                file_id,
+                last_modified,
+                changed,
+            }
+        } else {
+            let allocated_size = blocks * 512;
+            FileInfo::File {
+                size: if apparent { allocated_size } else { size },
+                allocated_size,
+                volume_id,
+                file_id,
+                links,
+                last_modified,
+            }
+        }
+    }
+
+    fn system_time(secs: i64, nanos: i64) -> SystemTime {
+        let since_epoch = Duration::new(secs.unsigned_abs(), 0);
+        let time = if secs >= 0 {
+            SystemTime::UNIX_EPOCH + since_epoch
+        } else {
+            SystemTime::UNIX_EPOCH - since_epoch
+        };
+        time + Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use super::{DirEntry, EntryKind};
+    use crate::FileInfo;
+    use std::fs;
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    pub(super) struct Dir {
+        path: PathBuf,
+    }
+
+    impl Dir {
+        pub(super) fn open(path: &Path) -> io::Result<Self> {
+            Ok(Dir {
+                path: path.to_path_buf(),
+            })
+        }
+
+        pub(super) fn open_at(&self, entry: &DirEntry) -> io::Result<Self> {
+            Ok(Dir {
+                path: self.path.join(&entry.name),
+            })
+        }
+
+        pub(super) fn entries(&self) -> io::Result<Vec<io::Result<DirEntry>>> {
+            Ok(fs::read_dir(&self.path)?
+                .map(|entry| {
+                    let entry = entry?;
+                    let kind = match entry.file_type() {
+                        Ok(t) if t.is_dir() => EntryKind::Directory,
+                        Ok(t) if t.is_file() || t.is_symlink() => EntryKind::File,
+                        _ => EntryKind::Unknown,
+                    };
+                    Ok(DirEntry {
+                        name: entry.file_name(),
+                        kind,
+                    })
+                })
+                .collect())
+        }
+
+        pub(super) fn stat_at(&self, entry: &DirEntry, apparent: bool) -> io::Result<FileInfo> {
+            FileInfo::stat(&self.path.join(&entry.name), apparent)
+        }
+
+        pub(super) fn generation(&self) -> Option<u64> {
+            None
+        }
+
+        /// Opens a regular file, as opening a FIFO would block.
+        pub(super) fn open_file(&self, entry: &DirEntry) -> io::Result<fs::File> {
+            let path = self.path.join(&entry.name);
+            if !fs::symlink_metadata(&path)?.is_file() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "not a regular file",
+                ));
+            }
+            fs::File::open(path)
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use super::{DirEntry, EntryKind, FsBackend};
+use crate::FileInfo;
+use std::collections::{BTreeSet, HashMap};
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::SystemTime;
+
+/// Volume id of every entry of a [`MemoryFs`].
+const VOLUME_ID: u64 = 1;
+
+/// Allocation unit of files in a [`MemoryFs`].
+const BLOCK_SIZE: u64 = 4096;
+
+/// A filesystem held in memory, for tests and virtual sources.
+///
+/// Entries are created with their missing parents, at the time set with
+/// [`MemoryFs::set_time`], which also becomes the modification time of the
+/// directories they are added to or removed from, as on a real filesystem.
+pub struct MemoryFs {
+    paths: HashMap<PathBuf, u64>,
+    inodes: HashMap<u64, Inode>,
+    next_id: u64,
+    time: SystemTime,
+    listings: AtomicU64,
+    /// OS error codes opening directories fails with.
+    open_errors: HashMap<PathBuf, i32>,
+}
+
+struct Inode {
+    size: u64,
+    links: u64,
+    last_modified: SystemTime,
+    /// Status change time, set to the current time by any change.
+    changed: SystemTime,
+    /// Names of the entries of a directory, `None` for files.
+    children: Option<BTreeSet<OsString>>,
+}
+
+impl Default for MemoryFs {
+    fn default() -> Self {
+        MemoryFs::new()
+    }
+}
+
+impl MemoryFs {
+    /// An empty filesystem with only its root `/`.
+    pub fn new() -> Self {
+        let mut fs = MemoryFs {
+            paths: HashMap::new(),
+            inodes: HashMap::new(),
+            next_id: 1,
+            time: SystemTime::UNIX_EPOCH,
+            listings: AtomicU64::new(0),
+            open_errors: HashMap::new(),
+        };
+        fs.add_dir("/");
+        fs
+    }
+
+    /// Sets the time of the changes that follow.
+    pub fn set_time(&mut self, time: SystemTime) {
+        self.time = time;
+    }
+
+    /// Number of directories listed so far, to check what scans read.
+    pub fn listings(&self) -> u64 {
+        self.listings.load(Ordering::Relaxed)
+    }
+
+    /// Makes opening the directory at `path` fail with the OS error `code`.
+    pub fn set_open_error(&mut self, path: impl AsRef<Path>, code: i32) {
+        self.open_errors.insert(path.as_ref().to_path_buf(), code);
+    }
+
+    /// Adds a directory, if it does not exist yet.
+    pub fn add_dir(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        if !self.paths.contains_key(path) {
+            let children = Some(BTreeSet::new());
+            let id = self.add_inode(0, children);
+            self.link(path, id);
+        }
+    }
+
+    /// Adds a file of `size` bytes, taking whole 4 KiB blocks on disk.
+    pub fn add_file(&mut self, path: impl AsRef<Path>, size: u64) {
+        let id = self.add_inode(size, None);
+        self.link(path.as_ref(), id);
+    }
+
+    /// Adds a hard link at `path` to the file at `target`.
+    ///
+    /// Panics if `target` is not a file.
+    pub fn add_hard_link(&mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) {
+        let target = target.as_ref();
+        let id = match self.paths.get(target) {
+            Some(&id) if self.inodes[&id].children.is_none() => id,
+            _ => panic!("{} is not a file", target.display()),
+        };
+        self.link(path.as_ref(), id);
+    }
+
+    /// Sets the modification time of an entry.
+    ///
+    /// Panics if there is no entry at `path`.
+    pub fn set_modified(&mut self, path: impl AsRef<Path>, time: SystemTime) {
+        let now = self.time;
+        let inode = self.inode_mut(path.as_ref());
+        inode.last_modified = time;
+        inode.changed = now;
+    }
+
+    /// Changes the size of a file, which also updates its modification time.
+    ///
+    /// Panics if there is no entry at `path`.
+    pub fn set_size(&mut self, path: impl AsRef<Path>, size: u64) {
+        let time = self.time;
+        let inode = self.inode_mut(path.as_ref());
+        inode.size = size;
+        inode.last_modified = time;
+        inode.changed = time;
+    }
+
+    /// Removes an entry, and everything below it for a directory.
+    pub fn remove(&mut self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let id = match self.paths.remove(path) {
+            Some(id) => id,
+            None => return,
+        };
+        if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            // The parent is already gone when it is being removed as well
+            if let Some(parent) = self.paths.get(parent) {
+                let parent = self.inodes.get_mut(parent).unwrap();
+                parent.children.as_mut().unwrap().remove(name);
+                parent.last_modified = self.time;
+                parent.changed = self.time;
+            }
+        }
+        let inode = self.inodes.get_mut(&id).unwrap();
+        inode.links -= 1;
+        inode.changed = self.time;
+        let children = inode.children.replace(BTreeSet::new());
+        if inode.links == 0 {
+            self.inodes.remove(&id);
+        } else if children.is_none() {
+            inode.children = None;
+        }
+        for child in children.into_iter().flatten() {
+            self.remove(path.join(child));
+        }
+    }
+
+    /// Moves an entry, and everything below it for a directory. Moved
+    /// entries keep their id and modification time, but not their status
+    /// change time.
+    ///
+    /// Panics if there is no entry at `from`.
+    pub fn rename(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        let mut moved = self
+            .paths
+            .iter()
+            .filter(|(path, _)| path.starts_with(from))
+            .map(|(path, &id)| (path.clone(), id, self.inodes[&id].last_modified))
+            .collect::<Vec<_>>();
+        assert!(!moved.is_empty(), "{} does not exist", from.display());
+        // Parents are linked again before their children
+        moved.sort_by_key(|(path, _, _)| path.components().count());
+
+        // An extra link keeps the inodes alive while they have no path
+        for (_, id, _) in &moved {
+            self.inodes.get_mut(id).unwrap().links += 1;
+        }
+        self.remove(from);
+        for (path, id, _) in &moved {
+            self.link(&to.join(path.strip_prefix(from).unwrap()), *id);
+            self.inodes.get_mut(id).unwrap().links -= 1;
+        }
+        for (_, id, last_modified) in moved {
+            let inode = self.inodes.get_mut(&id).unwrap();
+            inode.last_modified = last_modified;
+            inode.changed = self.time;
+        }
+    }
+
+    fn add_inode(&mut self, size: u64, children: Option<BTreeSet<OsString>>) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.inodes.insert(
+            id,
+            Inode {
+                size,
+                links: 0,
+                last_modified: self.time,
+                changed: self.time,
+                children,
+            },
+        );
+        id
+    }
+
+    /// Adds `path` as a name of inode `id`, creating missing parents.
+    fn link(&mut self, path: &Path, id: u64) {
+        if let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            self.add_dir(parent);
+            let time = self.time;
+            let parent = self.inode_mut(parent);
+            match &mut parent.children {
+                Some(children) => children.insert(name.to_os_string()),
+                None => panic!("{} is not a directory", path.display()),
+            };
+            parent.last_modified = time;
+            parent.changed = time;
+        }
+        let inode = self.inodes.get_mut(&id).unwrap();
+        inode.links += 1;
+        inode.changed = self.time;
+        self.paths.insert(path.to_path_buf(), id);
+    }
+
+    fn inode_mut(&mut self, path: &Path) -> &mut Inode {
+        match self.paths.get(path) {
+            Some(id) => self.inodes.get_mut(id).unwrap(),
+            None => panic!("{} does not exist", path.display()),
+        }
+    }
+
+    fn file_info(&self, path: &Path, apparent: bool) -> io::Result<FileInfo> {
+        let (id, inode) = self
+            .paths
+            .get(path)
+            .map(|id| (*id, &self.inodes[id]))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        Ok(match inode.children {
+            Some(_) => FileInfo::Directory {
+                volume_id: VOLUME_ID,
+                file_id: id,
+                last_modified: inode.last_modified,
+                changed: inode.changed,
+            },
+            None => {
+                let allocated_size = inode.size.div_ceil(BLOCK_SIZE) * BLOCK_SIZE;
+                FileInfo::File {
+                    size: if apparent { allocated_size } else { inode.size },
+                    allocated_size,
+                    volume_id: VOLUME_ID,
+                    file_id: id,
+                    links: inode.links,
+                    last_modified: inode.last_modified,
+                }
+            }
+        })
+    }
+}
+
+impl FsBackend for MemoryFs {
+    type Dir = PathBuf;
+
+    fn stat(&self, path: &Path, apparent: bool) -> io::Result<FileInfo> {
+        self.file_info(path, apparent)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+
+    fn open_at(&self, parent: &PathBuf, entry: &DirEntry) -> io::Result<PathBuf> {
+        let path = parent.join(&entry.name);
+        match self.open_errors.get(&path) {
+            Some(&code) => Err(io::Error::from_raw_os_error(code)),
+            None => Ok(path),
+        }
+    }
+
+    fn entries(&self, dir: &PathBuf) -> io::Result<Vec<io::Result<DirEntry>>> {
+        self.listings.fetch_add(1, Ordering::Relaxed);
+        let inode = self
+            .paths
+            .get(dir)
+            .map(|id| &self.inodes[id])
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let children = inode
+            .children
+            .as_ref()
+            .ok_or_else(|| io::Error::other("Not a directory"))?;
+        Ok(children
+            .iter()
+            .map(|name| {
+                let id = self.paths[&dir.join(name)];
+                let kind = match self.inodes[&id].children {
+                    Some(_) => EntryKind::Directory,
+                    None => EntryKind::File,
+                };
+                Ok(DirEntry {
+                    name: name.clone(),
+                    kind,
+                })
+            })
+            .collect())
+    }
+
+    fn stat_at(&self, parent: &PathBuf, entry: &DirEntry, apparent: bool) -> io::Result<FileInfo> {
+        self.file_info(&parent.join(&entry.name), apparent)
+    }
+}
@@ -1,14 +1,30 @@
-use clap::Parser;
-use dirstat_rs::{DiskItem, FileInfo};
+use clap::{ArgEnum, Parser};
+use dirstat_rs::cache::Cache;
+use dirstat_rs::escape::escape_name;
+use dirstat_rs::progress::ProgressSnapshot;
+use dirstat_rs::quota::{Limits, QuotaSource};
+use dirstat_rs::{
+    AnalyzeConfig, CancelToken, DiskItem, FileInfo, IoLimit, Progress, ScanOptions, SortBy,
+    SortKey, TargetsAnalysis, TreeEntry,
+};
 use is_terminal::IsTerminal;
+use rayon::ThreadPoolBuilder;
 use std::env;
 use std::error::Error;
+use std::ffi::OsStr;
 use std::io;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use termcolor::{Buffer, BufferWriter, Color, ColorChoice, ColorSpec, WriteColor};
+use terminal_size::Width;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
 const INDENT_COLOR: Option<Color> = Some(Color::Rgb(75, 75, 75));
+/// Names are never truncated below this many columns, even if the line overflows.
+const MIN_NAME_WIDTH: usize = 12;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
 
 mod shape {
     pub const INDENT: &str = "│";
@@ -16,14 +32,37 @@ mod shape {
     pub const LAST: &str = "└──";
     pub const ITEM: &str = "├──";
     pub const _ITEM_WITH_CHILDREN: &str = "├─┬";
-    pub const SPACING: &str = "──";
+    pub const BAR_FULL: &str = "█";
+    pub const BAR_PARTS: [&str; 7] = ["▏", "▎", "▍", "▌", "▋", "▊", "▉"];
+    pub const BAR_EMPTY: &str = "░";
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    let config = Config::from_args();
-    let current_dir = env::current_dir()?;
-    let target_dir = config.target_dir.as_ref().unwrap_or(&current_dir);
-    let file_info = FileInfo::from_path(target_dir, config.apparent)?;
+    let mut config = Config::from_args();
+    let targets = if config.target_dirs.is_empty() {
+        vec![env::current_dir()?]
+    } else {
+        config.target_dirs.clone()
+    };
+    if cfg!(not(feature = "archives")) && config.look_into_archives {
+        return Err("--look-into-archives needs ds built with the archives feature".into());
+    }
+
+    if let Some(source) = &config.quota {
+        let limits = Limits::new(source.clone())?;
+        // Errors such as quotas being off would otherwise leave the column empty
+        if let Some(Err(e)) = targets.first().map(|target| limits.limit(target)) {
+            eprintln!("Warning: Failed to read limits - {}", e);
+        }
+        config.limits = Some(limits);
+        if !config
+            .columns
+            .iter()
+            .any(|column| matches!(column, Column::Quota))
+        {
+            config.columns.push(Column::Quota);
+        }
+    }
 
     let color_choice = if std::io::stdout().is_terminal() {
         ColorChoice::Auto
@@ -34,77 +73,458 @@ fn main() -> Result<(), Box<dyn Error>> {
     let stdout = BufferWriter::stdout(color_choice);
     let mut buffer = stdout.buffer();
 
-    if !config.json {
-        println!("\nAnalyzing: {}\n", target_dir.display())
+    if !config.json && !config.csv {
+        let names = targets
+            .iter()
+            .map(|target| target.display().to_string())
+            .collect::<Vec<_>>();
+        println!("\nAnalyzing: {}\n", names.join(", "))
     };
 
-    let analysed = match file_info {
-        FileInfo::Directory { volume_id, .. } => {
-            DiskItem::from_analyze(target_dir, config.apparent, volume_id, config.max_depth + 1)?
+    let cancel = CancelToken::new();
+    let handler_cancel = cancel.clone();
+    ctrlc::set_handler(move || {
+        if handler_cancel.is_cancelled() {
+            // Second interrupt, give up on the partial results
+            std::process::exit(130);
         }
-        _ => return Err(format!("{} is not a directory!", target_dir.display()).into()),
+        handler_cancel.cancel();
+    })?;
+
+    let mut options = ScanOptions {
+        cancel: Some(cancel),
+        deadline: config
+            .time_limit
+            .map(|secs| Instant::now() + Duration::from_secs(secs)),
+        pool: config
+            .threads
+            .map(|threads| ThreadPoolBuilder::new().num_threads(threads).build())
+            .transpose()?
+            .map(Arc::new),
+        io_limit: config.max_io.map(|max_io| Arc::new(IoLimit::new(max_io))),
+        look_into_archives: config.look_into_archives,
+        ..ScanOptions::default()
     };
+    let reporter = if !config.no_progress && io::stderr().is_terminal() {
+        let progress = Arc::new(Progress::new());
+        options.progress = Some(Arc::clone(&progress));
+        Some(progress.report_every(PROGRESS_INTERVAL, show_progress))
+    } else {
+        None
+    };
+    let mut analysis = match &config.cache {
+        Some(cache_path) => analyze_with_cache(&targets, cache_path, &config, options)?,
+        None => DiskItem::from_analyze_targets(
+            &targets,
+            config.apparent,
+            config.max_depth + 1,
+            &options,
+        ),
+    };
+    if let Some(reporter) = reporter {
+        reporter.finish();
+        eprint!("\r\x1b[2K");
+    }
+    let mut analysed = Vec::new();
+    for (target, item) in targets.iter().zip(analysis.items.iter_mut()) {
+        match item {
+            Ok(item) => {
+                item.sort(config.sort_by());
+                analysed.push((target, &*item));
+            }
+            Err(e) if targets.len() == 1 => return Err(e.clone().into()),
+            Err(e) => eprintln!("Error: {}", e),
+        }
+    }
+
+    let incomplete = analysed.iter().any(|(_, item)| item.incomplete);
 
     if config.json {
-        let serialized = serde_json::to_string(&analysed)?;
+        let serialized = match analysed.as_slice() {
+            [(_, item)] => serde_json::to_string(item)?,
+            _ => serde_json::to_string(&analysed.iter().map(|x| x.1).collect::<Vec<_>>())?,
+        };
         writeln!(&mut buffer, "{}", serialized)?;
+    } else if config.csv {
+        writeln!(
+            &mut buffer,
+            "path,disk_size,allocated_size,file_count,last_modified"
+        )?;
+        for (target, item) in &analysed {
+            show_csv(item, target, &mut buffer)?;
+        }
     } else {
-        show(&analysed, &config, &DisplayInfo::new(), &mut buffer)?;
+        for (i, (target, item)) in analysed.iter().enumerate() {
+            if i > 0 {
+                writeln!(&mut buffer)?;
+            }
+            show(target, item, &config, &mut buffer)?;
+        }
+        if targets.len() > 1 {
+            show_summary(&targets, &analysis, &mut buffer)?;
+        }
+    }
+    if incomplete {
+        eprintln!(
+            "Warning: scan was stopped early or ran out of file descriptors, sizes only cover what was analyzed."
+        );
     }
 
     stdout.print(&buffer)?;
     Ok(())
 }
 
-fn show(item: &DiskItem, conf: &Config, info: &DisplayInfo, buffer: &mut Buffer) -> io::Result<()> {
-    // Show self
-    show_item(item, info, buffer)?;
-    // Recursively show children
+/// Scans a single target, taking the sizes of cold folders from the cache at
+/// `cache_path` and saving it back with the folders scanned.
+fn analyze_with_cache(
+    targets: &[PathBuf],
+    cache_path: &Path,
+    config: &Config,
+    options: ScanOptions,
+) -> Result<TargetsAnalysis, Box<dyn Error>> {
+    let target = match targets {
+        [target] => target,
+        _ => return Err("--cache works with a single target directory".into()),
+    };
+    let volume_id = match FileInfo::from_path(target, config.apparent)? {
+        FileInfo::Directory { volume_id, .. } => volume_id,
+        _ => return Err(format!("{} is not a directory!", target.display()).into()),
+    };
+    let new_cache = || Cache::new(volume_id, target, SystemTime::UNIX_EPOCH);
+    let mut cache = match Cache::load(cache_path) {
+        Ok(cache) if cache.validate(target, volume_id).is_ok() => cache,
+        Ok(_) => new_cache(),
+        Err(_) if !cache_path.exists() => new_cache(),
+        Err(e) => {
+            eprintln!("Warning: Failed to load cache - {}", e);
+            new_cache()
+        }
+    };
+
+    let analyze_config = AnalyzeConfig {
+        root_dev: volume_id,
+        cache_valid_duration: Duration::from_secs(60 * 60 * 24 * config.cache_days),
+        // Folders within the shown depth are always listed
+        parent_cold_duration: Duration::MAX,
+        apparent: config.apparent,
+        sort: Some(config.sort_by()),
+        options,
+    };
+    let item = DiskItem::with_cache(target, analyze_config, config.max_depth + 1, &cache);
+    if let Ok(item) = &item {
+        cache.prune_after(item);
+        cache.expire = SystemTime::now() + Duration::from_secs(60 * 60 * config.cache_expire_hours);
+        cache.save(cache_path)?;
+    }
+    let (disk_size, allocated_size, file_count) = match &item {
+        Ok(item) => (item.disk_size, item.allocated_size, item.file_count),
+        Err(_) => (0, 0, 0),
+    };
+    Ok(TargetsAnalysis {
+        items: vec![item.map_err(|e| format!("{}: {}", target.display(), e))],
+        nested: Vec::new(),
+        disk_size,
+        allocated_size,
+        file_count,
+    })
+}
+
+/// Overwrites the status line on stderr.
+fn show_progress(progress: &ProgressSnapshot) {
+    let status = format!(
+        "{} files, {} dirs, {}, {} errors: ",
+        progress.files,
+        progress.dirs,
+        human_bytes::human_bytes(progress.bytes as f64),
+        progress.errors
+    );
+    let width = terminal_size::terminal_size_of(io::stderr())
+        .map(|(Width(w), _)| w as usize)
+        .unwrap_or(80);
+    let dir = progress.current_dir.to_string_lossy();
+    let dir = truncate(&dir, width.saturating_sub(status.width() + 1));
+    eprint!("\r\x1b[2K{}{}", status, dir);
+}
+
+fn show_summary(
+    targets: &[PathBuf],
+    analysis: &TargetsAnalysis,
+    buffer: &mut Buffer,
+) -> io::Result<()> {
+    let scanned = analysis.items.iter().filter(|item| item.is_ok()).count();
+    writeln!(buffer)?;
+    buffer.set_color(ColorSpec::new().set_bold(true))?;
+    write!(
+        buffer,
+        "Total: {}",
+        human_bytes::human_bytes(analysis.disk_size as f64)
+    )?;
+    buffer.reset()?;
+    writeln!(
+        buffer,
+        " in {} files, {} of {} targets scanned",
+        analysis.file_count,
+        scanned,
+        targets.len()
+    )?;
+    for &i in &analysis.nested {
+        writeln!(
+            buffer,
+            "  {} lies inside another target and is counted once",
+            targets[i].display()
+        )?;
+    }
+    Ok(())
+}
+
+fn show(path: &Path, item: &DiskItem, conf: &Config, buffer: &mut Buffer) -> io::Result<()> {
+    let mut rows = Vec::new();
+    collect_rows(item, conf, DisplayInfo::new(path), &mut rows);
+
+    let widths = conf
+        .columns
+        .iter()
+        .map(|column| match column {
+            Column::Bar => conf.bar_width,
+            _ => rows
+                .iter()
+                .map(|(item, info)| column.text(item, info).width())
+                .max()
+                .unwrap_or(0),
+        })
+        .collect::<Vec<_>>();
+    let columns_width: usize = widths.iter().map(|w| w + 1).sum();
+    let term_width = if std::io::stdout().is_terminal() {
+        terminal_size::terminal_size().map(|(Width(w), _)| w as usize)
+    } else {
+        None
+    };
+
+    for (item, info) in &rows {
+        show_item(item, info, conf, &widths, buffer)?;
+        // Name, truncated to what is left of the terminal line
+        let tree_width = info.indents.width() + info.prefix().width() + 1;
+        let name = escape_name(&item.name);
+        let name = match term_width {
+            Some(term_width) => {
+                let available = term_width.saturating_sub(columns_width + tree_width);
+                truncate(&name, available.max(MIN_NAME_WIDTH))
+            }
+            None => name.into_owned(),
+        };
+        buffer.reset()?;
+        writeln!(buffer, "{}", name)?;
+    }
+    Ok(())
+}
+
+fn show_csv(item: &DiskItem, path: &Path, buffer: &mut Buffer) -> io::Result<()> {
+    for TreeEntry { path, item, .. } in item.depth_first(path) {
+        let last_modified = item
+            .last_modified
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        writeln!(
+            buffer,
+            "{},{},{},{},{}",
+            csv_field(&escape_name(path.as_os_str())),
+            item.disk_size,
+            item.allocated_size,
+            item.file_count,
+            last_modified
+        )?;
+    }
+    Ok(())
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+fn collect_rows<'a>(
+    item: &'a DiskItem,
+    conf: &Config,
+    info: DisplayInfo,
+    rows: &mut Vec<(&'a DiskItem, DisplayInfo)>,
+) {
+    let mut info = info;
+    // Only directories have quotas, and only those shown have their children
+    if let (Some(limits), Some(_)) = (&conf.limits, &item.children) {
+        info.limit = limits.limit(&info.path).ok().flatten();
+    }
+    rows.push((item, info.clone()));
+    // Recursively collect children
     if info.level < conf.max_depth {
         if let Some(children) = &item.children {
+            // Members of an archive add up to more than its size on disk
+            let total = children
+                .iter()
+                .map(|child| child.disk_size)
+                .sum::<u64>()
+                .max(item.disk_size);
             let children = children
                 .iter()
-                .map(|child| (child, size_fraction(child, item)))
+                .map(|child| (child, size_fraction(child, total)))
                 .filter(|&(_, fraction)| fraction > conf.min_percent)
                 .collect::<Vec<_>>();
 
             if let Some((last_child, children)) = children.split_last() {
                 for &(child, fraction) in children.iter() {
-                    show(child, conf, &info.add_item(fraction), buffer)?;
+                    collect_rows(child, conf, info.add_item(fraction, &child.name), rows);
                 }
                 let &(child, fraction) = last_child;
-                show(child, conf, &info.add_last(fraction), buffer)?;
+                collect_rows(child, conf, info.add_last(fraction, &child.name), rows);
             }
         }
     }
-    Ok(())
 }
 
-fn show_item(item: &DiskItem, info: &DisplayInfo, buffer: &mut Buffer) -> io::Result<()> {
+fn show_item(
+    item: &DiskItem,
+    info: &DisplayInfo,
+    conf: &Config,
+    widths: &[usize],
+    buffer: &mut Buffer,
+) -> io::Result<()> {
+    // Columns
+    for (column, &width) in conf.columns.iter().zip(widths) {
+        match column {
+            Column::Percent | Column::Bar => {
+                buffer.set_color(ColorSpec::new().set_fg(info.color()))?
+            }
+            Column::Quota if info.over_limit(item) => {
+                buffer.set_color(ColorSpec::new().set_fg(Some(Color::Red)).set_bold(true))?
+            }
+            _ => buffer.reset()?,
+        }
+        if let Column::Bar = column {
+            let filled = bar(info.fraction, width);
+            write!(buffer, "{}", filled)?;
+            buffer.set_color(ColorSpec::new().set_fg(INDENT_COLOR))?;
+            write!(
+                buffer,
+                "{} ",
+                shape::BAR_EMPTY.repeat(width - filled.width())
+            )?;
+        } else {
+            write!(
+                buffer,
+                "{:>width$} ",
+                column.text(item, info),
+                width = width
+            )?;
+        }
+    }
     // Indentation
     buffer.set_color(ColorSpec::new().set_fg(INDENT_COLOR))?;
-    write!(buffer, "{}{}", info.indents, info.prefix())?;
-    // Percentage
-    buffer.set_color(ColorSpec::new().set_fg(info.color()))?;
-    write!(buffer, " {:.2}% ", info.fraction)?;
-    // Disk size
-    buffer.reset()?;
-    write!(
-        buffer,
-        "[{}]",
-        human_bytes::human_bytes(item.disk_size as f64),
-    )?;
-    // Arrow
-    buffer.set_color(ColorSpec::new().set_fg(INDENT_COLOR))?;
-    write!(buffer, " {} ", shape::SPACING)?;
-    // Name
-    buffer.reset()?;
-    writeln!(buffer, "{}", item.name)?;
+    write!(buffer, "{}{} ", info.indents, info.prefix())?;
     Ok(())
 }
 
-fn size_fraction(child: &DiskItem, parent: &DiskItem) -> f64 {
-    100.0 * (child.disk_size as f64 / parent.disk_size as f64)
+fn size_fraction(child: &DiskItem, total: u64) -> f64 {
+    100.0 * (child.disk_size as f64 / total as f64)
+}
+
+/// Renders `fraction` percent of `width` cells using eighth blocks.
+fn bar(fraction: f64, width: usize) -> String {
+    let eighths = ((fraction / 100.0) * (width * 8) as f64).round() as usize;
+    let eighths = eighths.min(width * 8);
+    let (full, part) = (eighths / 8, eighths % 8);
+    let mut bar = shape::BAR_FULL.repeat(full);
+    if part > 0 {
+        bar.push_str(shape::BAR_PARTS[part - 1]);
+    }
+    bar
+}
+
+/// Shortens `name` to at most `width` columns, marking the cut with an ellipsis.
+fn truncate(name: &str, width: usize) -> String {
+    if name.width() <= width {
+        return name.to_owned();
+    }
+    let mut truncated = String::new();
+    let mut used = 0;
+    for c in name.chars() {
+        let w = c.width().unwrap_or(0);
+        if used + w + 1 > width {
+            break;
+        }
+        used += w;
+        truncated.push(c);
+    }
+    truncated.push('…');
+    truncated
+}
+
+/// Formats a timestamp as `YYYY-MM-DD HH:MM` in UTC.
+fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let (days, rem) = (secs / 86400, secs % 86400);
+    // Civil date from days since epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60
+    )
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum Column {
+    /// Share of the parent directory.
+    Percent,
+    /// Share of the parent directory as a bar graph.
+    Bar,
+    /// Size as measured for the tree (see `-a`).
+    Size,
+    /// Allocation size on disk.
+    Allocated,
+    /// Number of files.
+    Count,
+    /// Most recent modification time.
+    Mtime,
+    /// Share of the limit of the directory used, in red when over it (see `--quota`).
+    Quota,
+}
+
+impl Column {
+    fn text(&self, item: &DiskItem, info: &DisplayInfo) -> String {
+        match self {
+            Column::Percent => format!("{:.2}%", info.fraction),
+            Column::Bar => String::new(),
+            Column::Size => human_bytes::human_bytes(item.disk_size as f64),
+            Column::Allocated => human_bytes::human_bytes(item.allocated_size as f64),
+            Column::Count => item.file_count.to_string(),
+            Column::Mtime => format_time(item.last_modified),
+            Column::Quota => match info.limit {
+                Some(limit) => format!(
+                    "{:.0}% of {}",
+                    100.0 * item.disk_size as f64 / limit as f64,
+                    human_bytes::human_bytes(limit as f64)
+                ),
+                None => String::new(),
+            },
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -113,36 +533,49 @@ struct DisplayInfo {
     level: usize,
     last: bool,
     indents: String,
+    path: PathBuf,
+    /// Size limit of the directory, with `--quota`.
+    limit: Option<u64>,
 }
 
 impl DisplayInfo {
-    fn new() -> Self {
+    fn new(path: &Path) -> Self {
         Self {
             fraction: 100.0,
             level: 0,
             last: true,
             indents: String::new(),
+            path: path.to_path_buf(),
+            limit: None,
         }
     }
     // TODO: Consume or mut instead of cloning
-    fn add_item(&self, fraction: f64) -> Self {
+    fn add_item(&self, fraction: f64, name: &OsStr) -> Self {
         Self {
             fraction,
             level: self.level + 1,
             last: false,
             indents: self.indents.clone() + self.indent() + "  ",
+            path: self.path.join(name),
+            limit: None,
         }
     }
 
-    fn add_last(&self, fraction: f64) -> Self {
+    fn add_last(&self, fraction: f64, name: &OsStr) -> Self {
         Self {
             fraction,
             level: self.level + 1,
             last: true,
             indents: self.indents.clone() + self.indent() + "  ",
+            path: self.path.join(name),
+            limit: None,
         }
     }
 
+    fn over_limit(&self, item: &DiskItem) -> bool {
+        self.limit.is_some_and(|limit| item.disk_size > limit)
+    }
+
     fn indent(&self) -> &'static str {
         if self.last {
             " "
@@ -186,7 +619,8 @@ struct Config {
     min_percent: f64,
 
     #[clap(parse(from_os_str))]
-    target_dir: Option<PathBuf>,
+    /// Directories to analyze, the current one by default.
+    target_dirs: Vec<PathBuf>,
 
     #[clap(short = 'a')]
     /// Apparent size on disk.
@@ -197,6 +631,101 @@ struct Config {
     #[clap(short = 'j')]
     /// Output sorted json.
     json: bool,
+
+    #[clap(long, conflicts_with = "json")]
+    /// Output sorted csv, one line per entry.
+    csv: bool,
+
+    #[clap(short = 's', long, arg_enum, default_value = "size")]
+    /// Sort entries by this key. Sizes, counts and times are listed
+    /// largest/newest first, names in ascending natural order.
+    sort: SortArg,
+
+    #[clap(short = 'r', long)]
+    /// Reverse the sort order.
+    reverse: bool,
+
+    #[clap(
+        short = 'c',
+        long,
+        arg_enum,
+        value_delimiter = ',',
+        default_value = "percent,size"
+    )]
+    /// Comma separated columns shown in front of each entry.
+    columns: Vec<Column>,
+
+    #[clap(long, default_value = "20")]
+    /// Width of the `bar` column.
+    bar_width: usize,
+
+    #[clap(long)]
+    /// Do not show scan progress on stderr.
+    no_progress: bool,
+
+    #[clap(short = 't', long)]
+    /// Stop scanning after this many seconds and show what was found so far.
+    /// Ctrl-C does the same at any time.
+    time_limit: Option<u64>,
+
+    #[clap(long)]
+    /// Number of scanning threads, one per CPU by default.
+    threads: Option<usize>,
+
+    #[clap(long)]
+    /// Maximum number of directory listings and stat calls in flight at once.
+    max_io: Option<usize>,
+
+    #[clap(long)]
+    /// Show .tar, .tar.gz and .zip files as directories of their members,
+    /// with uncompressed sizes. Archives still count for their size on disk.
+    look_into_archives: bool,
+
+    #[clap(long, parse(from_os_str))]
+    /// Take the sizes of cold folders below the shown depth from this cache
+    /// file, and save it back. File counts and allocated sizes of cached
+    /// folders are not known.
+    cache: Option<PathBuf>,
+
+    #[clap(long, default_value = "7")]
+    /// Folders not modified for this many days are cold.
+    cache_days: u64,
+
+    #[clap(long, default_value = "24")]
+    /// Rebuild the cache from scratch after this many hours.
+    cache_expire_hours: u64,
+
+    #[clap(long)]
+    /// Compare sizes with limits, and add the `quota` column: `user` or
+    /// `project` for the disk quotas of the owner or project of each
+    /// directory (Linux), or else the path of a TOML file of limits.
+    quota: Option<QuotaSource>,
+
+    #[clap(skip)]
+    limits: Option<Limits>,
+}
+
+#[derive(ArgEnum, Clone, Copy, Debug)]
+enum SortArg {
+    Size,
+    Name,
+    Count,
+    Mtime,
+}
+
+impl Config {
+    fn sort_by(&self) -> SortBy {
+        let (key, descending) = match self.sort {
+            SortArg::Size => (SortKey::Size, true),
+            SortArg::Name => (SortKey::Name, false),
+            SortArg::Count => (SortKey::Count, true),
+            SortArg::Mtime => (SortKey::Mtime, true),
+        };
+        SortBy {
+            key,
+            descending: descending != self.reverse,
+        }
+    }
 }
 
 fn parse_percent(src: &str) -> Result<f64, String> {
@@ -1,141 +1,771 @@
 use std::error::Error;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Write};
-use std::iter::FromIterator;
+use std::fmt::Display;
+use std::io;
+use std::net::TcpListener;
 use std::path::{Path, PathBuf};
-use std::str::FromStr;
-use std::time::{Duration, SystemTime};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
-use clap::Parser;
-use dashmap::{DashMap, DashSet};
-use dirstat_rs::{AnalyzeConfig, DiskItem, FileInfo};
-use serde::{Deserialize, Serialize};
+use clap::{Parser, Subcommand};
+use dirstat_rs::cache::{Cache, Generation};
+use dirstat_rs::drift::Drift;
+use dirstat_rs::escape::escape_name;
+use dirstat_rs::index::DirIndex;
+use dirstat_rs::progress::ProgressSnapshot;
+use dirstat_rs::prom::{self, respond, Format, Metrics};
+use dirstat_rs::quota::{Limits, QuotaSource};
+use dirstat_rs::targets::{self, DriftInvalidation, Labels, Target};
+use dirstat_rs::volume::volume_usage;
+use dirstat_rs::{
+    priority, AnalyzeConfig, DiskItem, FileInfo, IoLimit, Progress, RateLimit, ScanOptions,
+};
+use rayon::ThreadPoolBuilder;
+use serde_json::json;
 use tracing::info;
 
+/// HTTP requests answered at once, beyond which new connections are closed.
+const MAX_CONNECTIONS: usize = 8;
+
 fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt::init();
+    // Logs must not end up among the metrics printed on stdout
+    tracing_subscriber::fmt().with_writer(io::stderr).init();
 
     let config = Config::from_args();
-    if config.valid_days > config.parent_valid_days {
-        return Err("Parent valid days (-p) should be larger than valid days (-t) ".into());
+    if let Some(Command::Cache(command)) = &config.command {
+        return run_cache_command(command);
+    }
+    if config.nice {
+        // Before the scanning threads are spawned, as they inherit priorities
+        if let Err(e) = priority::lower_priority() {
+            eprintln!("Warning: Failed to lower priority - {}", e);
+        }
+    }
+    let targets = config.targets()?;
+    for target in &targets {
+        if target.valid_days > target.parent_valid_days {
+            return Err(format!(
+                "Parent valid days (-p) should be larger than valid days (-t) for {}",
+                target.path.display()
+            )
+            .into());
+        }
     }
 
-    let default_dir = PathBuf::from_str("/home")?;
-    let target_dir = config.target_dir.as_ref().unwrap_or(&default_dir);
-    let file_info = FileInfo::from_path(target_dir, config.apparent)?;
-    let cache = config.cache.as_ref().map(|p| Cache::from_file(p));
-    let cache = match cache {
-        Some(Ok(cache)) => {
-            if cache.target_dir != *target_dir {
-                eprintln!(
-                    "Warning: target dir mismatched. Expected {}, found {}. Ignored.",
-                    target_dir.to_string_lossy(),
-                    cache.target_dir.to_string_lossy()
+    match &config.listen {
+        Some(addr) => serve(&config, &targets, addr),
+        None => match &config.textfile {
+            Some(path) => write_textfile(&config, &targets, path),
+            None => {
+                print!(
+                    "{}",
+                    update(&config, &targets)?.render(&config, config.format())
                 );
-                DashMap::new()
-            } else if SystemTime::now() > cache.expire {
-                eprintln!("Warning: cache expired.");
-                DashMap::new()
-            } else if let FileInfo::Directory { volume_id, .. } = file_info {
-                if volume_id != cache.volume_id {
-                    eprintln!("Warning: volume id mismatched. Ignored.");
-                    DashMap::new()
-                } else {
-                    info!("loading cache");
-                    DashMap::from_iter(cache.data)
-                }
-            } else {
-                info!("Loading cache");
-                DashMap::from_iter(cache.data)
+                Ok(())
             }
-        }
+        },
+    }
+}
+
+/// Writes the output of a scan to the textfile at `path`, see
+/// [`prom::write_textfile`].
+fn write_textfile(config: &Config, targets: &[Target], path: &Path) -> Result<(), Box<dyn Error>> {
+    let result = update(config, targets);
+    let output = result
+        .as_ref()
+        .ok()
+        .map(|report| report.render(config, config.format()));
+    prom::write_textfile(path, output.as_deref(), config.format())?;
+    result.map(|_| ())
+}
+
+/// Scans all targets once, and returns their output together. Nothing is
+/// returned when any of them fails.
+fn update(config: &Config, targets: &[Target]) -> Result<Report, Box<dyn Error>> {
+    let mut report = Report::default();
+    // Read for every scan, so that changed limits show up
+    let limits = config.quota.clone().map(Limits::new).transpose()?;
+    for target in targets {
+        update_target(config, target, limits.as_ref(), &mut report)?;
+    }
+    Ok(report)
+}
+
+/// Scans a target dir, updating its cache and index, and adds its metrics to
+/// `report`, comparing the sizes of its children with `limits` if any.
+fn update_target(
+    config: &Config,
+    target: &Target,
+    limits: Option<&Limits>,
+    report: &mut Report,
+) -> Result<(), Box<dyn Error>> {
+    let started = Instant::now();
+    let target_dir = &target.path;
+    let volume_id = match FileInfo::from_path(target_dir, config.apparent)? {
+        FileInfo::Directory { volume_id, .. } => volume_id,
+        _ => return Err(format!("{} is not a directory!", target_dir.display()).into()),
+    };
+    let expire = SystemTime::now()
+        .checked_add(Duration::from_secs(60 * 60 * target.expire_hours))
+        .unwrap_or(SystemTime::UNIX_EPOCH);
+    let new_cache = || Cache::new(volume_id, target_dir, expire);
+    let mut cache = match target.cache.as_ref().map(|p| Cache::load(p)) {
+        Some(Ok(cache)) => match cache.validate(target_dir, volume_id) {
+            Ok(()) => {
+                info!("loading cache");
+                cache
+            }
+            Err(e) => {
+                eprintln!("Warning: {}. Ignored.", e);
+                new_cache()
+            }
+        },
         Some(Err(e)) => {
             eprintln!("Warning: Failed to load cache - {}", e);
-            DashMap::new()
-        }
-        None => {
-            // eprintln!("Cache not found");
-            DashMap::new()
+            new_cache()
         }
+        None => new_cache(),
     };
 
+    cache.verify_paths = config.verify_paths;
+    let cache_loaded = cache.len();
     if cache.is_empty() {
         info!("new cache created");
     } else {
         info!("cache loaded. size = {}", cache.len());
     }
 
-    let cache_used = DashSet::new();
-    let max_depth = if !cache.is_empty() {
-        config.max_depth + 1
-    } else {
-        2
+    let index = match &target.index {
+        Some(path) if path.exists() => match DirIndex::load(path) {
+            Ok(index) => {
+                info!("index loaded. size = {}", index.len());
+                Some(index)
+            }
+            Err(e) => {
+                eprintln!("Warning: Failed to load index - {}", e);
+                Some(DirIndex::new())
+            }
+        },
+        Some(_) => Some(DirIndex::new()),
+        None => None,
+    }
+    .map(Arc::new);
+
+    let progress = Arc::new(Progress::new());
+    let options = ScanOptions {
+        progress: Some(Arc::clone(&progress)),
+        pool: config
+            .threads
+            .map(|threads| ThreadPoolBuilder::new().num_threads(threads).build())
+            .transpose()?
+            .map(Arc::new),
+        io_limit: config.max_io.map(|max_io| Arc::new(IoLimit::new(max_io))),
+        stat_rate: config
+            .max_stats_per_sec
+            .map(|per_second| Arc::new(RateLimit::new(per_second))),
+        index: index.clone(),
+        ..ScanOptions::default()
     };
 
-    let vol_id;
-    let analysed = match file_info {
-        FileInfo::Directory { volume_id, .. } => {
-            vol_id = volume_id;
-            if config.cache.is_some() {
-                DiskItem::with_cache(
-                    target_dir,
-                    AnalyzeConfig {
-                        root_dev: volume_id,
-                        cache_valid_duration: Duration::from_secs(60 * 60 * 24 * config.valid_days),
-                        parent_cold_duration: Duration::from_secs(
-                            60 * 60 * 24 * config.parent_valid_days,
-                        ),
-                        apparent: config.apparent,
-                        sort: false,
-                    },
-                    max_depth,
-                    &cache,
-                    &cache_used,
-                )?
-            } else {
-                DiskItem::from_analyze(target_dir, config.apparent, volume_id, max_depth)?
+    let analyze_config = || AnalyzeConfig {
+        root_dev: volume_id,
+        cache_valid_duration: Duration::from_secs(60 * 60 * 24 * target.valid_days),
+        parent_cold_duration: Duration::from_secs(60 * 60 * 24 * target.parent_valid_days),
+        apparent: config.apparent,
+        sort: None,
+        options: options.clone(),
+    };
+    let scan = |cache: &Cache| -> Result<DiskItem, Box<dyn Error>> {
+        let max_depth = if !cache.is_empty() {
+            target.depth + 1
+        } else {
+            2
+        };
+        if target.cache.is_some() {
+            DiskItem::with_cache(target_dir, analyze_config(), max_depth, cache)
+        } else {
+            DiskItem::from_analyze_with(target_dir, config.apparent, volume_id, max_depth, &options)
+        }
+    };
+    let mut analysed = scan(&cache)?;
+
+    let unaccounted = match target.drift_threshold {
+        Some(threshold) => {
+            let drift = Drift {
+                usage: volume_usage(target_dir)?,
+                threshold,
+                invalidation: target.drift_invalidation,
+            };
+            if drift.drifted(&analysed, &cache) {
+                eprintln!(
+                    "Warning: {} bytes unaccounted for, {} when the cache was built. Rescanning.",
+                    drift.unaccounted(&analysed),
+                    cache.unaccounted.unwrap_or_default()
+                );
             }
+            let unaccounted =
+                drift.rescan(&mut analysed, &mut cache, |analysed, cache, child| {
+                    match child {
+                        Some(name) => analysed.rescan_child(
+                            target_dir,
+                            name,
+                            analyze_config(),
+                            target.depth + 1,
+                            cache,
+                        ),
+                        None => {
+                            // Counts are those of the scan giving the metrics
+                            progress.reset();
+                            *analysed = scan(cache)?;
+                            Ok(())
+                        }
+                    }
+                })?;
+            Some(unaccounted)
         }
-        _ => return Err(format!("{} is not a directory!", target_dir.display()).into()),
+        None => None,
     };
 
-    if !config.show_folder_size {
-        show(&analysed);
-    } else {
-        println!("{}", analysed.disk_size);
-    }
-
-    if let Some(cache_path) = config.cache {
-        let save = Cache::new(
-            vol_id,
-            target_dir,
-            cache
-                .into_iter()
-                .filter(|x| cache_used.contains(&x.0))
-                .collect(),
-            SystemTime::now()
-                .checked_add(Duration::from_secs(60 * 60 * config.expire_hours))
-                .unwrap_or(SystemTime::UNIX_EPOCH),
-        );
-        save.save_to_file(&cache_path)?;
+    // Without a deadline, only running out of file descriptors leaves folders
+    // out, whose sizes would then look smaller
+    if analysed.incomplete {
+        return Err(format!(
+            "scan of {} ran out of file descriptors",
+            target_dir.display()
+        )
+        .into());
+    }
+    report.sizes.push(analysed.disk_size);
+    show(&mut report.metrics, config, target, &analysed);
+    if let Some(limits) = limits {
+        show_quotas(&mut report.metrics, config, target, &analysed, limits);
     }
+    if let Some(unaccounted) = unaccounted {
+        show_unaccounted(&mut report.metrics, config, target, &analysed, unaccounted);
+    }
+
+    let mut cache_stats = None;
+    if let Some(cache_path) = &target.cache {
+        let used = cache.used();
+        cache.prune_after(&analysed);
+        cache.expire = expire;
+        cache.save(cache_path)?;
+        cache_stats = Some(CacheStats {
+            loaded: cache_loaded,
+            used,
+            written: cache.len(),
+            lookups: cache.lookups(),
+            hits: cache.hits(),
+        });
+    }
+
+    if let (Some(index), Some(index_path)) = (index, &target.index) {
+        index.prune();
+        index.save(index_path)?;
+    }
+
+    let stats = ScanStats {
+        duration: started.elapsed(),
+        progress: progress.snapshot(),
+        cache: cache_stats,
+    };
+    show_scan_stats(&mut report.metrics, target, &stats);
     Ok(())
 }
 
-fn show(analyzed: &DiskItem) {
-    let name = analyzed.name.replace(' ', "_");
-    println!("# HELP node_{name}_folder_size_bytes Summarized sizes of subdirectories under folder {name}");
-    println!("# TYPE node_{name}_folder_size_bytes gauge");
+/// What a scan of a target did, for the metrics about the scan itself.
+struct ScanStats {
+    /// Time taken by the scan, including rescans and saving files.
+    duration: Duration,
+    progress: ProgressSnapshot,
+    cache: Option<CacheStats>,
+}
+
+struct CacheStats {
+    loaded: usize,
+    used: usize,
+    written: usize,
+    lookups: u64,
+    hits: u64,
+}
+
+fn show_scan_stats(metrics: &mut Metrics, target: &Target, stats: &ScanStats) {
+    let path = escape_name(target.path.as_os_str()).into_owned();
+    let mut gauge = |name: &str,
+                     unit: Option<&str>,
+                     help: &str,
+                     extra: &[(&str, &str)],
+                     value: &dyn Display| {
+        let mut labels = vec![("path", path.as_str())];
+        labels.extend_from_slice(extra);
+        labels.extend(
+            target
+                .labels
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.as_str())),
+        );
+        metrics.family(name, unit, help).sample(&labels, value);
+    };
+
+    let finished = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    gauge(
+        "home_sizes_prom_scan_duration_seconds",
+        Some("seconds"),
+        "Duration of the last successful scan of a target",
+        &[],
+        &stats.duration.as_secs_f64(),
+    );
+    gauge(
+        "home_sizes_prom_last_success_timestamp_seconds",
+        Some("seconds"),
+        "Unix time at which the last successful scan of a target ended",
+        &[],
+        &finished.as_secs(),
+    );
+    gauge(
+        "home_sizes_prom_scan_files",
+        None,
+        "Files visited by the last successful scan of a target",
+        &[],
+        &stats.progress.files,
+    );
+    gauge(
+        "home_sizes_prom_scan_dirs",
+        None,
+        "Directories visited by the last successful scan of a target",
+        &[],
+        &stats.progress.dirs,
+    );
+    let mut error_kinds = stats
+        .progress
+        .error_kinds
+        .iter()
+        .map(|(kind, count)| (format!("{:?}", kind), *count))
+        .collect::<Vec<_>>();
+    error_kinds.sort();
+    for (kind, count) in &error_kinds {
+        gauge(
+            "home_sizes_prom_scan_errors",
+            None,
+            "Entries the last successful scan of a target could not read, by kind of error",
+            &[("kind", kind)],
+            count,
+        );
+    }
+
+    if let Some(cache) = &stats.cache {
+        let help = "Folders in the cache of a target: loaded before the last successful scan, used by it, and written after it";
+        for (state, count) in [
+            ("loaded", cache.loaded),
+            ("used", cache.used),
+            ("written", cache.written),
+        ] {
+            gauge(
+                "home_sizes_prom_cache_entries",
+                None,
+                help,
+                &[("state", state)],
+                &count,
+            );
+        }
+        gauge(
+            "home_sizes_prom_cache_lookups",
+            None,
+            "Cold folders looked up in the cache by the last successful scan of a target",
+            &[],
+            &cache.lookups,
+        );
+        let ratio = match cache.lookups {
+            0 => 0.0,
+            lookups => cache.hits as f64 / lookups as f64,
+        };
+        gauge(
+            "home_sizes_prom_cache_hit_ratio",
+            Some("ratio"),
+            "Share of the lookups of the last successful scan of a target which found a size in the cache",
+            &[],
+            &ratio,
+        );
+    }
+}
+
+/// Metrics of the targets, or only their sizes with `-s`.
+#[derive(Default)]
+struct Report {
+    metrics: Metrics,
+    sizes: Vec<u64>,
+}
+
+impl Report {
+    fn render(&self, config: &Config, format: Format) -> String {
+        if config.show_folder_size {
+            self.sizes
+                .iter()
+                .map(|size| format!("{}\n", size))
+                .collect()
+        } else {
+            self.metrics.render(format)
+        }
+    }
+}
+
+/// Name of a metric of `analyzed`, either fixed or holding its name.
+fn metric_name(config: &Config, analyzed: &DiskItem, metric: &str) -> String {
+    if config.fixed_metric_names {
+        format!("node_folder_{}", metric)
+    } else {
+        format!("node_{}_folder_{}", escape_name(&analyzed.name), metric)
+    }
+}
+
+/// Labels of the samples of a target, after the `name` of a child if any.
+fn target_labels<'a>(
+    config: &Config,
+    target: &'a Target,
+    path: &'a str,
+    name: Option<&'a str>,
+) -> Vec<(&'a str, &'a str)> {
+    let mut labels = Vec::new();
+    if let Some(name) = name {
+        labels.push(("name", name));
+    }
+    if config.fixed_metric_names {
+        labels.push(("path", path));
+    }
+    labels.extend(
+        target
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    );
+    labels
+}
+
+fn show(metrics: &mut Metrics, config: &Config, target: &Target, analyzed: &DiskItem) {
+    let path = escape_name(target.path.as_os_str());
+    let help = match config.fixed_metric_names {
+        true => "Summarized sizes of subdirectories under a folder".to_owned(),
+        false => format!(
+            "Summarized sizes of subdirectories under folder {}",
+            escape_name(&analyzed.name)
+        ),
+    };
+    let name = metric_name(config, analyzed, "size_bytes");
+    let family = metrics.family(&name, Some("bytes"), &help);
     for item in analyzed.children.as_ref().expect("BUG: Item has no child") {
-        println!(
-            "node_{}_folder_size_bytes{{name=\"{}\"}} {}",
-            name, item.name, item.disk_size
+        let child = escape_name(&item.name);
+        family.sample(
+            &target_labels(config, target, &path, Some(&child)),
+            item.disk_size,
+        );
+    }
+}
+
+/// Exports the limit of each child having one, and the share of it used.
+fn show_quotas(
+    metrics: &mut Metrics,
+    config: &Config,
+    target: &Target,
+    analyzed: &DiskItem,
+    limits: &Limits,
+) {
+    let path = escape_name(target.path.as_os_str());
+    let children = match limits.children(&target.path, analyzed) {
+        Ok(children) => children,
+        Err(e) => {
+            // Such as quotas being off, which is the same for all children
+            eprintln!(
+                "Warning: Failed to read limits under {} - {}",
+                target.path.display(),
+                e
+            );
+            return;
+        }
+    };
+    // Such as a home removed since the scan, or which cannot be opened
+    if let Some((child, e)) = children.skipped.first() {
+        eprintln!(
+            "Warning: Failed to read the limits of {} folders under {}, such as {} - {}",
+            children.skipped.len(),
+            target.path.display(),
+            child.name.to_string_lossy(),
+            e
         );
     }
+    let mut labels = vec![("path", path.as_ref())];
+    labels.extend(
+        target
+            .labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str())),
+    );
+    metrics
+        .family(
+            "home_sizes_prom_limit_errors",
+            None,
+            "Subdirectories of a target whose limit could not be read",
+        )
+        .sample(&labels, children.skipped.len());
+    let quotas = children
+        .limits
+        .iter()
+        .map(|(child, limit)| (escape_name(&child.name), child.disk_size, *limit))
+        .collect::<Vec<_>>();
+    if quotas.is_empty() {
+        return;
+    }
+
+    let name = metric_name(config, analyzed, "limit_bytes");
+    let family = metrics.family(
+        &name,
+        Some("bytes"),
+        "Size limits of subdirectories, from their quota",
+    );
+    for (child, _, limit) in &quotas {
+        family.sample(&target_labels(config, target, &path, Some(child)), limit);
+    }
+    let name = metric_name(config, analyzed, "usage_ratio");
+    let family = metrics.family(
+        &name,
+        Some("ratio"),
+        "Sizes of subdirectories divided by their limit",
+    );
+    for (child, size, limit) in &quotas {
+        family.sample(
+            &target_labels(config, target, &path, Some(child)),
+            *size as f64 / *limit as f64,
+        );
+    }
+}
+
+fn show_unaccounted(
+    metrics: &mut Metrics,
+    config: &Config,
+    target: &Target,
+    analyzed: &DiskItem,
+    unaccounted: i64,
+) {
+    let path = escape_name(target.path.as_os_str());
+    let help = match config.fixed_metric_names {
+        true => "Used bytes of the volume not found under a folder".to_owned(),
+        false => format!(
+            "Used bytes of the volume not found under folder {}",
+            escape_name(&analyzed.name)
+        ),
+    };
+    let name = metric_name(config, analyzed, "unaccounted_bytes");
+    metrics
+        .family(&name, Some("bytes"), &help)
+        .sample(&target_labels(config, target, &path, None), unaccounted);
+}
+
+/// Serves the output of the latest scan on `/metrics`, while scans run again
+/// every `--interval-minutes` in the background. Scrapes never wait for a
+/// scan, and get a 503 until the first one is done.
+fn serve(config: &Config, targets: &[Target], addr: &str) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr)?;
+    info!("listening on {}", listener.local_addr()?);
+    let latest: RwLock<Option<Arc<Report>>> = RwLock::new(None);
+    let interval = Duration::from_secs(60 * config.interval_minutes);
+    let active = AtomicUsize::new(0);
+
+    thread::scope(|scope| {
+        scope.spawn(|| loop {
+            let started = Instant::now();
+            match update(config, targets) {
+                Ok(report) => *latest.write().unwrap() = Some(Arc::new(report)),
+                // The output of the previous scan is kept
+                Err(e) => eprintln!("Error: Scan failed - {}", e),
+            }
+            info!("scan done in {:?}", started.elapsed());
+            thread::sleep(interval.saturating_sub(started.elapsed()));
+        });
+
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Warning: Failed to accept connection - {}", e);
+                    continue;
+                }
+            };
+            // Slow clients are cut short by `respond`, but could still pile up
+            if active.load(Ordering::Relaxed) >= MAX_CONNECTIONS {
+                info!("too many connections, closing one");
+                continue;
+            }
+            active.fetch_add(1, Ordering::Relaxed);
+            let active = &active;
+            let report = latest.read().unwrap().clone();
+            scope.spawn(move || {
+                let render = report
+                    .as_deref()
+                    .map(|report| move |format| report.render(config, format));
+                if let Err(e) = respond(&stream, render) {
+                    info!("failed to respond - {}", e);
+                }
+                active.fetch_sub(1, Ordering::Relaxed);
+            });
+        }
+    });
+    Ok(())
+}
+
+/// Runs a cache maintenance subcommand.
+fn run_cache_command(command: &CacheCommand) -> Result<(), Box<dyn Error>> {
+    match command {
+        CacheCommand::Dump { file } => {
+            let cache = Cache::load(file)?;
+            println!("{}", serde_json::to_string_pretty(&dump(&cache))?);
+        }
+        CacheCommand::Stats { file } => show_stats(&Cache::load(file)?),
+        CacheCommand::Prune {
+            file,
+            older_than_days,
+        } => {
+            let cache = Cache::load(file)?;
+            let removed =
+                cache.prune_older_than(Duration::from_secs(60 * 60 * 24 * older_than_days));
+            cache.save(file)?;
+            println!("{} entries removed, {} left", removed, cache.len());
+        }
+        CacheCommand::Invalidate { file, path } => {
+            let cache = Cache::load(file)?;
+            let relative = if path.is_absolute() {
+                path.strip_prefix(&cache.target_dir).map_err(|_| {
+                    format!(
+                        "{} is not under {}",
+                        path.display(),
+                        cache.target_dir.display()
+                    )
+                })?
+            } else {
+                path
+            };
+            let removed = cache.invalidate(relative);
+            cache.save(file)?;
+            println!("{} entries removed, {} left", removed, cache.len());
+        }
+    }
+    Ok(())
+}
+
+/// Cache as JSON, with the path of each folder when it still is there.
+fn dump(cache: &Cache) -> serde_json::Value {
+    let mut entries = cache.entries();
+    entries.sort_by(|(_, a), (_, b)| a.path.cmp(&b.path));
+    let entries = entries
+        .into_iter()
+        .map(|(key, size)| {
+            let path = match size.path.as_os_str().is_empty() {
+                true => cache.target_dir.clone(),
+                false => cache.target_dir.join(&size.path),
+            };
+            // The recorded path is only trusted if it leads to the same folder
+            let resolved = match FileInfo::from_path(&path, false) {
+                Ok(FileInfo::Directory {
+                    volume_id, file_id, ..
+                }) if volume_id == key.volume_id && file_id == key.file_id => {
+                    Some(path.to_string_lossy().into_owned())
+                }
+                _ => None,
+            };
+            let generation = match key.generation {
+                Generation::Inode(generation) => json!({ "inode": generation }),
+                Generation::Changed(changed) => json!({ "changed": unix_secs(changed) }),
+            };
+            json!({
+                "volume_id": key.volume_id,
+                "file_id": key.file_id,
+                "generation": generation,
+                "relative_path": size.path.to_string_lossy(),
+                "path": resolved,
+                "last_modified": unix_secs(size.last_modified),
+                "scanned": unix_secs(size.scanned),
+                "disk_size": size.disk_size,
+            })
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "target_dir": cache.target_dir.to_string_lossy(),
+        "volume_id": cache.volume_id,
+        "expire": unix_secs(cache.expire),
+        "unaccounted": cache.unaccounted,
+        "entries": entries,
+    })
+}
+
+fn show_stats(cache: &Cache) {
+    const DAY: u64 = 60 * 60 * 24;
+    // Upper bounds of the age buckets, in days
+    const BUCKETS: [(u64, &str); 4] = [
+        (1, "< 1 day"),
+        (7, "< 7 days"),
+        (30, "< 30 days"),
+        (365, "< 1 year"),
+    ];
+
+    let now = SystemTime::now();
+    let entries = cache.entries();
+    let mut counts = [0usize; BUCKETS.len() + 2];
+    for (_, size) in &entries {
+        let bucket = if size.scanned == SystemTime::UNIX_EPOCH {
+            BUCKETS.len() + 1
+        } else {
+            let age = now
+                .duration_since(size.scanned)
+                .unwrap_or_default()
+                .as_secs()
+                / DAY;
+            BUCKETS
+                .iter()
+                .position(|&(days, _)| age < days)
+                .unwrap_or(BUCKETS.len())
+        };
+        counts[bucket] += 1;
+    }
+
+    println!("target dir: {}", cache.target_dir.display());
+    println!("volume id:  {}", cache.volume_id);
+    println!("entries:    {}", entries.len());
+    let total = entries
+        .iter()
+        .filter(|(_, size)| size.path.as_os_str().is_empty())
+        .map(|(_, size)| size.disk_size)
+        .next();
+    if let Some(total) = total {
+        println!("total size: {}", total);
+    }
+    if let Some(unaccounted) = cache.unaccounted {
+        println!("unaccounted: {}", unaccounted);
+    }
+    match cache.expire.duration_since(now) {
+        Ok(left) => println!("expires in: {} hours", left.as_secs() / 60 / 60),
+        Err(e) => println!("expired:    {} hours ago", e.duration().as_secs() / 60 / 60),
+    }
+    println!("measured:");
+    let labels = BUCKETS
+        .iter()
+        .map(|&(_, label)| label)
+        .chain(["older", "unknown"]);
+    for (label, count) in labels.zip(counts) {
+        println!("  {:<10} {}", label, count);
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
 }
 
 #[derive(Parser)]
 struct Config {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
     #[clap(short = 'd', default_value = "2")]
     /// Maximum recursion depth in directory for caches.
     max_depth: usize,
@@ -154,6 +784,16 @@ struct Config {
     /// Cache file path
     cache: Option<PathBuf>,
 
+    #[clap(long, parse(from_os_str))]
+    /// Directory index file path. Directories unchanged since the previous
+    /// run are not listed again, only their entries are stat'ed.
+    index: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Only use cached sizes of folders still at the same path, so that
+    /// moved folders are scanned again.
+    verify_paths: bool,
+
     #[clap(short = 'e', default_value_t = 24u64)]
     expire_hours: u64,
 
@@ -165,46 +805,152 @@ struct Config {
     #[clap(short = 'p', default_value_t = 365u64)]
     parent_valid_days: u64,
 
+    #[clap(long)]
+    /// Number of scanning threads, one per CPU by default.
+    threads: Option<usize>,
+
+    #[clap(long)]
+    /// Maximum number of directory listings and stat calls in flight at once.
+    max_io: Option<usize>,
+
+    #[clap(long)]
+    /// Run with idle I/O priority and lowest CPU priority.
+    nice: bool,
+
+    #[clap(long)]
+    /// Maximum number of stat calls per second.
+    max_stats_per_sec: Option<u32>,
+
+    #[clap(long)]
+    /// Export the bytes used on the volume but not found under the target
+    /// dir, and rescan when they moved by more than this percentage of the
+    /// volume size since the cache was built.
+    drift_threshold: Option<f64>,
+
+    #[clap(long, arg_enum, default_value = "full")]
+    /// What to rescan on drift: everything, or the largest cached children
+    /// of the target one by one until the drift is back under the threshold.
+    drift_invalidation: DriftInvalidation,
+
+    #[clap(
+        long,
+        parse(from_os_str),
+        conflicts_with_all = &[
+            "target-dir",
+            "max-depth",
+            "valid-days",
+            "parent-valid-days",
+            "expire-hours",
+            "cache",
+            "index",
+            "drift-threshold",
+            "drift-invalidation",
+        ]
+    )]
+    /// Scan the targets listed in this TOML file, instead of the target dir
+    /// given on the command line, and output their metrics together.
+    config: Option<PathBuf>,
+
+    #[clap(long, parse(from_os_str), conflicts_with = "listen")]
+    /// Write the metrics to this textfile, for the textfile collector of
+    /// node-exporter, instead of printing them. The file is replaced
+    /// atomically, and kept as it was when the scan fails.
+    textfile: Option<PathBuf>,
+
+    #[clap(long)]
+    /// Name metrics `node_folder_size_bytes` with a `path` label holding the
+    /// target dir, instead of `node_<target name>_folder_size_bytes`.
+    fixed_metric_names: bool,
+
+    #[clap(long)]
+    /// Print or write the metrics in the OpenMetrics format. Over HTTP, the
+    /// format is chosen by the client.
+    openmetrics: bool,
+
+    #[clap(long)]
+    /// Serve the metrics on http://ADDR/metrics instead of printing them,
+    /// scanning again every `--interval-minutes`.
+    listen: Option<String>,
+
+    #[clap(long, default_value_t = 60u64)]
+    /// Minutes between the starts of two scans when serving the metrics.
+    interval_minutes: u64,
+
+    #[clap(long)]
+    /// Also export the limit of each subdirectory and the share of it used:
+    /// `user` or `project` for the disk quotas of the owner or project of
+    /// each subdirectory (Linux), or else the path of a TOML file of limits.
+    quota: Option<QuotaSource>,
+
     #[clap(parse(from_os_str))]
     /// Analyze dir
     target_dir: Option<PathBuf>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Cache {
-    volume_id: u64,
-    expire: SystemTime,
-    target_dir: PathBuf,
-    data: Vec<(u64, (SystemTime, u64))>,
-}
-
-impl Cache {
-    pub fn from_file(cache_path: &Path) -> Result<Cache, Box<dyn Error>> {
-        let f = File::open(cache_path)?;
-        let reader = BufReader::new(f);
-        let cache: Cache = rmp_serde::from_read(reader)?;
-        Ok(cache)
-    }
-
-    pub fn new(
-        volume_id: u64,
-        target_dir: &Path,
-        data: Vec<(u64, (SystemTime, u64))>,
-        expire: SystemTime,
-    ) -> Cache {
-        Cache {
-            volume_id,
-            target_dir: target_dir.to_owned(),
-            data,
-            expire,
+impl Config {
+    /// Format of the metrics printed or written to a textfile.
+    fn format(&self) -> Format {
+        match self.openmetrics {
+            true => Format::OpenMetrics,
+            false => Format::Prometheus,
         }
     }
 
-    pub fn save_to_file(&self, cache_path: &Path) -> Result<(), Box<dyn Error>> {
-        let f = File::create(cache_path)?;
-        let mut writer = BufWriter::new(f);
-        let bin = rmp_serde::to_vec(self)?;
-        writer.write_all(&bin)?;
-        Ok(())
+    /// Targets of the config file, or the one given on the command line.
+    fn targets(&self) -> Result<Vec<Target>, Box<dyn Error>> {
+        if let Some(path) = &self.config {
+            return targets::load(path);
+        }
+        Ok(vec![Target {
+            path: self
+                .target_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from("/home")),
+            depth: self.max_depth,
+            valid_days: self.valid_days,
+            parent_valid_days: self.parent_valid_days,
+            expire_hours: self.expire_hours,
+            cache: self.cache.clone(),
+            index: self.index.clone(),
+            drift_threshold: self.drift_threshold,
+            drift_invalidation: self.drift_invalidation,
+            labels: Labels::new(),
+        }])
     }
 }
+
+#[derive(Subcommand)]
+enum Command {
+    /// Inspect or repair a cache file
+    #[clap(subcommand)]
+    Cache(CacheCommand),
+}
+
+#[derive(Subcommand)]
+enum CacheCommand {
+    /// Print the cache as JSON, with the current path of each folder
+    Dump {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Show the number of entries, their age and the expiry of the cache
+    Stats {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+    },
+    /// Remove the entries measured more than this many days ago
+    Prune {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+        #[clap(long)]
+        older_than_days: u64,
+    },
+    /// Remove the entries of a folder, the folders below it and above it
+    Invalidate {
+        #[clap(parse(from_os_str))]
+        file: PathBuf,
+        /// Folder, absolute or relative to the target dir of the cache
+        #[clap(parse(from_os_str))]
+        path: PathBuf,
+    },
+}
@@ -0,0 +1,294 @@
+//! Sizes of cold folders kept from one scan to the next.
+//!
+//! A [`Cache`] maps a folder to its modification time and disk size. Scans run
+//! with [`DiskItem::with_cache`](crate::DiskItem::with_cache) take the size of
+//! a folder from the cache when the folder has not been modified for long
+//! enough and still has the same modification time, and add the folders they
+//! fully scan. A cache belongs to one target directory on one volume and
+//! expires at a given time, after which it should be rebuilt.
+//!
+//! Folders are identified by a [`CacheKey`]. File ids are reused once a folder
+//! is deleted, so the key also holds a generation which differs between the
+//! successive folders of a file id. Folders also keep their path relative to
+//! the target directory, which lookups can be asked to check.
+//!
+//! A cache file starts with a header: [`MAGIC`], then as little endian
+//! integers the format version (`u32`), the length of the payload (`u64`) and
+//! its CRC-32 (`u32`). The payload is the cache in MessagePack. Files are
+//! replaced atomically, and those of version 0, which had no header, are
+//! migrated on load.
+//!
+//! A cache also keeps the bytes used on the volume that its last full scan did
+//! not account for, so that later runs can tell when cached sizes drift away
+//! from the real usage.
+
+use crate::persist::{read_header, write_with_header};
+use crate::DiskItem;
+use dashmap::{DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+/// First bytes of a cache file.
+pub const MAGIC: &[u8; 8] = b"DSTCACHE";
+
+/// Version of the cache files written.
+pub const VERSION: u32 = 1;
+
+pub struct Cache {
+    pub volume_id: u64,
+    pub target_dir: PathBuf,
+    pub expire: SystemTime,
+    /// Only use cached sizes of folders still at the same path.
+    pub verify_paths: bool,
+    /// Used bytes of the volume minus the size of the target directory, when
+    /// the cache was last rebuilt.
+    pub unaccounted: Option<i64>,
+    sizes: DashMap<CacheKey, CachedSize>,
+    /// Folders scans looked up or added since the cache was loaded.
+    used: DashSet<CacheKey>,
+    /// Lookups since the cache was loaded, and those which found a size.
+    lookups: AtomicU64,
+    hits: AtomicU64,
+}
+
+/// Identity of a cached folder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub volume_id: u64,
+    pub file_id: u64,
+    pub generation: Generation,
+}
+
+/// Tells apart folders which got the same file id one after the other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Generation {
+    /// Inode generation number, from `FS_IOC_GETVERSION`.
+    Inode(u64),
+    /// Status change time, for filesystems without generation numbers. It
+    /// also changes when the folder is renamed or its metadata changes.
+    Changed(SystemTime),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CachedSize {
+    pub last_modified: SystemTime,
+    pub disk_size: u64,
+    /// Path of the folder relative to the target directory.
+    pub path: PathBuf,
+    /// When the size was measured, `UNIX_EPOCH` if unknown.
+    pub scanned: SystemTime,
+}
+
+/// Payload of a cache file.
+#[derive(Serialize, Deserialize)]
+struct CacheFile {
+    volume_id: u64,
+    expire: SystemTime,
+    target_dir: PathBuf,
+    data: Vec<(CacheKey, CachedSize)>,
+    unaccounted: Option<i64>,
+}
+
+/// Payload of cache files of version 0, which had no header.
+#[derive(Deserialize)]
+struct CacheFileV0 {
+    volume_id: u64,
+    expire: SystemTime,
+    target_dir: PathBuf,
+    #[allow(dead_code)]
+    data: Vec<(u64, (SystemTime, u64))>,
+}
+
+impl From<CacheFileV0> for CacheFile {
+    /// Entries of version 0 are keyed by bare file ids, which cannot be told
+    /// apart from reused ones. They are dropped and rebuilt by the next scan.
+    fn from(file: CacheFileV0) -> Self {
+        CacheFile {
+            volume_id: file.volume_id,
+            expire: file.expire,
+            target_dir: file.target_dir,
+            data: Vec::new(),
+            unaccounted: None,
+        }
+    }
+}
+
+impl Cache {
+    /// An empty cache for `target_dir`, lying on `volume_id`.
+    pub fn new(volume_id: u64, target_dir: &Path, expire: SystemTime) -> Self {
+        Cache {
+            volume_id,
+            target_dir: target_dir.to_owned(),
+            expire,
+            verify_paths: false,
+            unaccounted: None,
+            sizes: DashMap::new(),
+            used: DashSet::new(),
+            lookups: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let bytes = fs::read(path)?;
+        let file = if bytes.starts_with(MAGIC) {
+            match read_header(&bytes, MAGIC)? {
+                (1, payload) => rmp_serde::from_slice::<CacheFile>(payload)?,
+                (version, _) => return Err(format!("unsupported cache version {}", version).into()),
+            }
+        } else {
+            rmp_serde::from_slice::<CacheFileV0>(&bytes)?.into()
+        };
+        Ok(Cache {
+            volume_id: file.volume_id,
+            target_dir: file.target_dir,
+            expire: file.expire,
+            verify_paths: false,
+            unaccounted: file.unaccounted,
+            sizes: file.data.into_iter().collect(),
+            used: DashSet::new(),
+            lookups: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let file = CacheFile {
+            volume_id: self.volume_id,
+            expire: self.expire,
+            target_dir: self.target_dir.clone(),
+            data: self
+                .sizes
+                .iter()
+                .map(|entry| (*entry.key(), entry.value().clone()))
+                .collect(),
+            unaccounted: self.unaccounted,
+        };
+        write_with_header(path, MAGIC, VERSION, &rmp_serde::to_vec(&file)?)?;
+        Ok(())
+    }
+
+    /// Checks that the cache was made for `target_dir` on `volume_id` and has
+    /// not expired, telling what does not match otherwise.
+    pub fn validate(&self, target_dir: &Path, volume_id: u64) -> Result<(), String> {
+        if self.target_dir != target_dir {
+            Err(format!(
+                "target dir mismatched. Expected {}, found {}",
+                target_dir.to_string_lossy(),
+                self.target_dir.to_string_lossy()
+            ))
+        } else if SystemTime::now() > self.expire {
+            Err("cache expired".to_owned())
+        } else if self.volume_id != volume_id {
+            Err("volume id mismatched".to_owned())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Adds the entries of `other`. Of two entries for the same folder, the
+    /// one with the latest modification time is kept.
+    pub fn merge(&self, other: Cache) {
+        for (key, size) in other.sizes {
+            let mut entry = self.sizes.entry(key).or_insert_with(|| size.clone());
+            if entry.last_modified < size.last_modified {
+                *entry = size;
+            }
+        }
+    }
+
+    /// Forgets the folders no scan used since the cache was loaded or last
+    /// pruned, such as deleted or recently modified ones.
+    pub fn prune(&self) {
+        self.sizes.retain(|key, _| self.used.contains(key));
+        self.used.clear();
+    }
+
+    /// Prunes the cache after a scan which gave `item`, unless the scan was
+    /// stopped early, as the folders it did not reach would be forgotten too.
+    pub fn prune_after(&self, item: &DiskItem) {
+        if !item.incomplete {
+            self.prune();
+        }
+    }
+
+    /// Forgets the sizes measured more than `age` ago, or at an unknown time.
+    /// Returns the number of folders forgotten.
+    pub fn prune_older_than(&self, age: Duration) -> usize {
+        let before = self.sizes.len();
+        let now = SystemTime::now();
+        self.sizes.retain(|_, size| {
+            size.scanned != SystemTime::UNIX_EPOCH
+                && now.duration_since(size.scanned).unwrap_or_default() <= age
+        });
+        before - self.sizes.len()
+    }
+
+    /// Forgets the folder at `path`, relative to the target directory, with
+    /// the folders below it and above it, whose sizes include its own.
+    /// Returns the number of folders forgotten.
+    pub fn invalidate(&self, path: &Path) -> usize {
+        let before = self.sizes.len();
+        self.sizes
+            .retain(|_, size| !(size.path.starts_with(path) || path.starts_with(&size.path)));
+        before - self.sizes.len()
+    }
+
+    /// All folders in the cache, in no particular order.
+    pub fn entries(&self) -> Vec<(CacheKey, CachedSize)> {
+        self.sizes
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect()
+    }
+
+    /// Number of folders in the cache.
+    pub fn len(&self) -> usize {
+        self.sizes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    /// Number of folders scans used since the cache was loaded or last pruned.
+    pub fn used(&self) -> usize {
+        self.used.len()
+    }
+
+    /// Number of lookups since the cache was loaded.
+    pub fn lookups(&self) -> u64 {
+        self.lookups.load(Ordering::Relaxed)
+    }
+
+    /// Number of lookups which found a size since the cache was loaded.
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Cached size of a folder, if it still has the same modification time,
+    /// and the same relative path when paths are verified.
+    pub fn lookup(&self, key: &CacheKey, last_modified: SystemTime, path: &Path) -> Option<u64> {
+        self.lookups.fetch_add(1, Ordering::Relaxed);
+        let cached = self.sizes.get(key)?;
+        let valid = cached.last_modified != SystemTime::UNIX_EPOCH
+            && cached.last_modified == last_modified
+            && (!self.verify_paths || cached.path == path);
+        if valid {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            self.used.insert(*key);
+            Some(cached.disk_size)
+        } else {
+            None
+        }
+    }
+
+    pub fn store(&self, key: CacheKey, size: CachedSize) {
+        self.sizes.insert(key, size);
+        self.used.insert(key);
+    }
+}
@@ -0,0 +1,102 @@
+//! Detection of folder sizes gone stale in a cache.
+//!
+//! Files may change deep inside a folder without touching the modification
+//! time of the folders above them, whose cached sizes then stay in use. This
+//! shows as a drift of the bytes used on the volume but not found under the
+//! target: when they move by more than a threshold since the cache was built,
+//! [`Drift::rescan`] scans folders again.
+
+use crate::cache::Cache;
+use crate::targets::DriftInvalidation;
+use crate::volume::VolumeUsage;
+use crate::DiskItem;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use tracing::info;
+
+pub struct Drift {
+    /// Usage of the volume holding the target.
+    pub usage: VolumeUsage,
+    /// Drift from which folders are scanned again, in percent of the volume size.
+    pub threshold: f64,
+    pub invalidation: DriftInvalidation,
+}
+
+impl Drift {
+    /// Bytes used on the volume but not found under `analyzed`.
+    pub fn unaccounted(&self, analyzed: &DiskItem) -> i64 {
+        self.usage.used as i64 - analyzed.disk_size as i64
+    }
+
+    /// Whether the unaccounted bytes of `analyzed` moved by more than the
+    /// threshold since `cache` was built.
+    pub fn drifted(&self, analyzed: &DiskItem, cache: &Cache) -> bool {
+        let drift = cache.unaccounted.map_or(0, |previous| {
+            (self.unaccounted(analyzed) - previous).unsigned_abs()
+        });
+        drift as f64 > self.usage.total as f64 * self.threshold / 100.0
+    }
+
+    /// Scans folders of `analyzed` again when it drifted from `cache`, and
+    /// returns its unaccounted bytes, which the cache keeps unless it has some
+    /// already.
+    ///
+    /// `scan` updates `analyzed` from the cache: with a name, by scanning that
+    /// child of the target again, and without, by scanning the whole target.
+    /// With [`DriftInvalidation::Subtree`], the cached children are invalidated
+    /// and scanned again one by one, largest first, until the drift is back
+    /// under the threshold.
+    pub fn rescan<F>(
+        &self,
+        analyzed: &mut DiskItem,
+        cache: &mut Cache,
+        mut scan: F,
+    ) -> Result<i64, Box<dyn Error>>
+    where
+        F: FnMut(&mut DiskItem, &Cache, Option<&OsStr>) -> Result<(), Box<dyn Error>>,
+    {
+        if self.drifted(analyzed, cache) {
+            let children = match self.invalidation {
+                DriftInvalidation::Full => Vec::new(),
+                DriftInvalidation::Subtree => cached_children(analyzed, cache),
+            };
+            for name in &children {
+                info!("rescanning {}", name.to_string_lossy());
+                cache.invalidate(Path::new(name));
+                scan(analyzed, cache, Some(name))?;
+                if !self.drifted(analyzed, cache) {
+                    break;
+                }
+            }
+            // Also when the size of the target itself came from the cache
+            if children.is_empty() {
+                cache.invalidate(Path::new(""));
+                scan(analyzed, cache, None)?;
+            }
+            cache.unaccounted = None;
+        }
+        let unaccounted = self.unaccounted(analyzed);
+        cache.unaccounted.get_or_insert(unaccounted);
+        Ok(unaccounted)
+    }
+}
+
+/// Children of the target whose folders are in the cache, largest first.
+fn cached_children(analyzed: &DiskItem, cache: &Cache) -> Vec<OsString> {
+    let cached = cache
+        .entries()
+        .into_iter()
+        .filter_map(|(_, size)| size.path.iter().next().map(OsStr::to_os_string))
+        .collect::<HashSet<_>>();
+    let mut children = analyzed
+        .children
+        .iter()
+        .flatten()
+        .filter(|item| cached.contains(&item.name))
+        .collect::<Vec<_>>();
+    children.sort_by_key(|item| Reverse(item.disk_size));
+    children.into_iter().map(|item| item.name.clone()).collect()
+}
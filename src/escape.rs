@@ -0,0 +1,107 @@
+//! Lossless text form of file names.
+//!
+//! Outputs are UTF-8 text, while file names may be any bytes (Unix) or any
+//! UTF-16 units (Windows). Names are written as is, except for:
+//!
+//! - `\\` for a backslash,
+//! - `\xHH` for a byte that is not part of valid UTF-8 (Unix),
+//! - `\uHHHH` for an unpaired UTF-16 surrogate (Windows).
+//!
+//! [`unescape_name`] gives back the exact original name.
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Write;
+use std::str::Chars;
+
+pub fn escape_name(name: &OsStr) -> Cow<'_, str> {
+    match name.to_str() {
+        Some(name) if !name.contains('\\') => Cow::Borrowed(name),
+        _ => Cow::Owned(escape(name)),
+    }
+}
+
+#[cfg(unix)]
+fn escape(name: &OsStr) -> String {
+    use std::os::unix::ffi::OsStrExt;
+
+    let mut escaped = String::with_capacity(name.len());
+    for chunk in name.as_bytes().utf8_chunks() {
+        escaped.push_str(&chunk.valid().replace('\\', "\\\\"));
+        for byte in chunk.invalid() {
+            write!(escaped, "\\x{:02X}", byte).unwrap();
+        }
+    }
+    escaped
+}
+
+#[cfg(windows)]
+fn escape(name: &OsStr) -> String {
+    use std::os::windows::ffi::OsStrExt;
+
+    let mut escaped = String::with_capacity(name.len());
+    for c in char::decode_utf16(name.encode_wide()) {
+        match c {
+            Ok('\\') => escaped.push_str("\\\\"),
+            Ok(c) => escaped.push(c),
+            Err(e) => write!(escaped, "\\u{:04X}", e.unpaired_surrogate()).unwrap(),
+        }
+    }
+    escaped
+}
+
+#[cfg(unix)]
+pub fn unescape_name(escaped: &str) -> Result<OsString, Box<dyn Error>> {
+    use std::os::unix::ffi::OsStringExt;
+
+    let mut name = Vec::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => name.push(b'\\'),
+                Some('x') => name.push(hex(&mut chars, 2, escaped)? as u8),
+                _ => return Err(invalid(escaped)),
+            },
+            c => name.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+        }
+    }
+    Ok(OsString::from_vec(name))
+}
+
+#[cfg(windows)]
+pub fn unescape_name(escaped: &str) -> Result<OsString, Box<dyn Error>> {
+    use std::os::windows::ffi::OsStringExt;
+
+    let mut name = Vec::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('\\') => name.push(u16::from(b'\\')),
+                Some('u') => name.push(hex(&mut chars, 4, escaped)? as u16),
+                _ => return Err(invalid(escaped)),
+            },
+            c => name.extend_from_slice(c.encode_utf16(&mut [0; 2])),
+        }
+    }
+    Ok(OsString::from_wide(&name))
+}
+
+/// Reads exactly `digits` hexadecimal digits.
+fn hex(chars: &mut Chars, digits: usize, escaped: &str) -> Result<u32, Box<dyn Error>> {
+    let mut value = 0;
+    for _ in 0..digits {
+        let digit = chars
+            .next()
+            .and_then(|c| c.to_digit(16))
+            .ok_or_else(|| invalid(escaped))?;
+        value = value * 16 + digit;
+    }
+    Ok(value)
+}
+
+fn invalid(escaped: &str) -> Box<dyn Error> {
+    format!("Invalid escape sequence in name {:?}", escaped).into()
+}
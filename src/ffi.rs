@@ -1,16 +1,17 @@
 #![cfg(windows)]
 
-use std::error::Error;
 use std::io;
 use std::iter::once;
 use std::os::windows::ffi::OsStrExt;
 use std::path::Path;
+use std::time::{Duration, SystemTime};
 use windows_sys::Win32::Foundation::GetLastError;
 use windows_sys::Win32::Foundation::NO_ERROR;
 use windows_sys::Win32::Storage::FileSystem::GetCompressedFileSizeW;
+use windows_sys::Win32::Storage::FileSystem::GetDiskFreeSpaceExW;
 use windows_sys::Win32::Storage::FileSystem::INVALID_FILE_SIZE;
 
-pub fn compressed_size(path: &Path) -> Result<u64, Box<dyn Error>> {
+pub fn compressed_size(path: &Path) -> io::Result<u64> {
     let wide = path_to_u16s(path);
     let mut high: u32 = 0;
 
@@ -20,13 +21,25 @@ pub fn compressed_size(path: &Path) -> Result<u64, Box<dyn Error>> {
     if low == INVALID_FILE_SIZE {
         let err = get_last_error();
         if err != NO_ERROR {
-            return Err(io::Error::last_os_error().into());
+            return Err(io::Error::last_os_error());
         }
     }
 
     Ok(u64::from(high) << 32 | u64::from(low))
 }
 
+/// Total and free bytes of the volume holding `path`.
+pub fn disk_space(path: &Path) -> io::Result<(u64, u64)> {
+    let wide = path_to_u16s(path);
+    let (mut total, mut free) = (0, 0);
+    let ok =
+        unsafe { GetDiskFreeSpaceExW(wide.as_ptr(), std::ptr::null_mut(), &mut total, &mut free) };
+    if ok == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((total, free))
+}
+
 /// inspired by [fn maybe_verbatim(path: &Path)](https://github.com/rust-lang/rust/blob/1f4681ad7a132755452c32a987ad0f0d075aa6aa/library/std/src/sys/windows/path.rs#L170)
 /// But function from std is calling winapi GetFullPathNameW in case if path is longer than 248.
 /// We are more optimistic and expect all path being absolute, so no API calls from this function.
@@ -75,6 +88,13 @@ fn path_to_u16s(path: &Path) -> Vec<u16> {
     }
 }
 
+/// Converts a FILETIME (100ns intervals since 1601-01-01) into a `SystemTime`.
+pub fn filetime_to_system_time(filetime: u64) -> SystemTime {
+    const UNIX_EPOCH_AS_FILETIME: u64 = 116_444_736_000_000_000;
+    let since_epoch = filetime.saturating_sub(UNIX_EPOCH_AS_FILETIME);
+    SystemTime::UNIX_EPOCH + Duration::from_nanos(since_epoch.saturating_mul(100))
+}
+
 fn get_last_error() -> u32 {
     unsafe { GetLastError() }
 }
@@ -0,0 +1,137 @@
+//! Listings of directories kept from one scan to the next.
+//!
+//! A [`DirIndex`] remembers the entries of every directory a scan listed,
+//! along with its modification and status change times. When a later scan
+//! finds a directory with the same times, its entries cannot have changed and
+//! the scan reuses them instead of listing the directory again. Entries are
+//! still stat'ed, so files growing in place and changes deeper in the tree are
+//! seen, while unchanged directories are never read again. Only listings are
+//! kept: sizes always come from the entries.
+//!
+//! An index file has the same header as a cache file, starting with [`MAGIC`]
+//! and followed by the index in MessagePack.
+//!
+//! An index is shared with scans through [`ScanOptions::index`](crate::ScanOptions)
+//! and saved with [`DirIndex::save`] for the next run.
+
+use crate::backend::DirEntry;
+use crate::persist::{read_header, write_with_header};
+use dashmap::{DashMap, DashSet};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// First bytes of an index file.
+pub const MAGIC: &[u8; 8] = b"DSTINDEX";
+
+/// Version of the index files written.
+pub const VERSION: u32 = 1;
+
+/// Listings of directories modified this recently are not kept, as another
+/// change within the same timestamp tick would go unnoticed.
+const RACY_DURATION: Duration = Duration::from_secs(2);
+
+/// Directories are keyed by volume id and file id.
+type Key = (u64, u64);
+
+#[derive(Default)]
+pub struct DirIndex {
+    dirs: DashMap<Key, IndexedDir>,
+    /// Directories scans went through since the index was loaded.
+    visited: DashSet<Key>,
+}
+
+/// A directory as it was when it was last listed.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct IndexedDir {
+    pub last_modified: SystemTime,
+    pub changed: SystemTime,
+    pub entries: Vec<DirEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    dirs: Vec<(Key, IndexedDir)>,
+}
+
+impl DirIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let bytes = fs::read(path)?;
+        let file: IndexFile = match read_header(&bytes, MAGIC)? {
+            (VERSION, payload) => rmp_serde::from_slice(payload)?,
+            (version, _) => return Err(format!("unsupported index version {}", version).into()),
+        };
+        Ok(DirIndex {
+            dirs: file.dirs.into_iter().collect(),
+            visited: DashSet::new(),
+        })
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        let file = IndexFile {
+            dirs: self
+                .dirs
+                .iter()
+                .map(|entry| (*entry.key(), entry.value().clone()))
+                .collect(),
+        };
+        write_with_header(path, MAGIC, VERSION, &rmp_serde::to_vec(&file)?)?;
+        Ok(())
+    }
+
+    /// Number of directories in the index.
+    pub fn len(&self) -> usize {
+        self.dirs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dirs.is_empty()
+    }
+
+    pub fn get(&self, volume_id: u64, file_id: u64) -> Option<IndexedDir> {
+        self.dirs
+            .get(&(volume_id, file_id))
+            .map(|dir| dir.value().clone())
+    }
+
+    /// Forgets the directories no scan went through since the index was
+    /// loaded or last pruned: deleted ones, but also those below a folder
+    /// whose size came from a folder cache.
+    pub fn prune(&self) {
+        self.dirs.retain(|key, _| self.visited.contains(key));
+        self.visited.clear();
+    }
+
+    /// Entries of a directory, if it has not changed since it was listed.
+    pub(crate) fn entries(
+        &self,
+        key: Key,
+        last_modified: SystemTime,
+        changed: SystemTime,
+    ) -> Option<Vec<DirEntry>> {
+        self.visited.insert(key);
+        let dir = self.dirs.get(&key)?;
+        let unchanged = last_modified != SystemTime::UNIX_EPOCH
+            && dir.last_modified == last_modified
+            && dir.changed == changed;
+        unchanged.then(|| dir.entries.clone())
+    }
+
+    /// Keeps a directory listed at `listed_at`.
+    pub(crate) fn insert(&self, key: Key, dir: IndexedDir, listed_at: SystemTime) {
+        let settled = listed_at
+            .duration_since(dir.last_modified)
+            .is_ok_and(|age| age > RACY_DURATION);
+        if settled {
+            self.dirs.insert(key, dir);
+        } else {
+            self.dirs.remove(&key);
+        }
+    }
+}
@@ -1,28 +1,405 @@
-use dashmap::{DashMap, DashSet};
+use dashmap::DashMap;
 use rayon::prelude::*;
-use serde::Serialize;
-// use tracing::info;
+use rayon::ThreadPool;
+use serde::{Serialize, Serializer};
+use std::cmp::Ordering;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::error::Error;
-use std::ffi::OsStr;
-use std::fs;
-use std::path::Path;
-use std::time::{Duration, SystemTime};
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::iter::Peekable;
+use std::ops::AddAssign;
+use std::path::{Component, Path, PathBuf};
+use std::str::Chars;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::info;
 
+#[cfg(feature = "archives")]
+mod archive;
+pub mod arena;
+pub mod backend;
+pub mod cache;
+pub mod drift;
+pub mod escape;
 mod ffi;
+pub mod index;
+pub mod persist;
+pub mod priority;
+pub mod progress;
+pub mod prom;
+pub mod quota;
+pub mod targets;
+pub mod volume;
+
+use backend::{DirEntry, EntryKind, FsBackend, RealFs};
+use cache::{Cache, CacheKey, CachedSize, Generation};
+use index::{DirIndex, IndexedDir};
+pub use progress::Progress;
 
 #[derive(Serialize)]
 pub struct DiskItem {
-    pub name: String,
+    /// File name, written with [`escape::escape_name`] in serialized outputs.
+    #[serde(serialize_with = "serialize_name")]
+    pub name: OsString,
     pub disk_size: u64,
+    /// Allocation size on disk, i.e. what `disk_size` would be with `apparent` set.
+    ///
+    /// Items restored from a folder cache only know their `disk_size`, so this
+    /// and `file_count` are zero for them.
+    pub allocated_size: u64,
+    /// Number of non-directory entries below this item (1 for a file).
+    pub file_count: u64,
+    /// Most recent modification time of this item or anything below it.
+    pub last_modified: SystemTime,
+    /// Set when the scan was stopped before this item was fully analyzed, or
+    /// ran out of file descriptors within it, in which case its sizes only
+    /// cover what was seen so far.
+    #[serde(skip_serializing_if = "is_false")]
+    pub incomplete: bool,
     pub children: Option<Vec<DiskItem>>,
 }
 
+/// Errors within a scan, which may cross threads.
+type ScanError = Box<dyn Error + Send + Sync>;
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+fn serialize_name<S: Serializer>(name: &OsStr, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&escape::escape_name(name))
+}
+
+/// Sizes accumulated by hardlinked files, so that repeated links can be
+/// subtracted from their common ancestor.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Usage {
+    disk_size: u64,
+    allocated_size: u64,
+    file_count: u64,
+}
+
+impl AddAssign for Usage {
+    fn add_assign(&mut self, rhs: Self) {
+        self.disk_size += rhs.disk_size;
+        self.allocated_size += rhs.allocated_size;
+        self.file_count += rhs.file_count;
+    }
+}
+
+impl Usage {
+    fn total<T: ScanTree>(items: &[T]) -> Self {
+        let mut total = Usage::default();
+        for item in items {
+            total += item.usage();
+        }
+        total
+    }
+
+    fn without(self, repeated: Usage) -> Self {
+        Usage {
+            disk_size: self.disk_size - repeated.disk_size,
+            allocated_size: self.allocated_size - repeated.allocated_size,
+            file_count: self.file_count - repeated.file_count,
+        }
+    }
+}
+
+/// Links seen so far below a directory, keyed by file id.
+type FileIdMap = DashMap<u64, Links>;
+
+struct Links {
+    /// Usage of a single link.
+    single: Usage,
+    /// Usage of all links beyond the first one.
+    repeated: Usage,
+}
+
+/// Records one more link to `file_id`.
+fn add_link(fileid_map: &FileIdMap, file_id: u64, usage: Usage) {
+    fileid_map
+        .entry(file_id)
+        .and_modify(|x| x.repeated += usage)
+        .or_insert(Links {
+            single: usage,
+            repeated: Usage::default(),
+        });
+}
+
+/// Moves the links seen in a subtree into the map of its parent, and returns
+/// the usage repeated within the subtree.
+fn merge_links(subtree: FileIdMap, parent: &FileIdMap) -> Usage {
+    let mut repeated = Usage::default();
+    for (file_id, links) in subtree.into_iter() {
+        repeated += links.repeated;
+        add_link(parent, file_id, links.single);
+    }
+    repeated
+}
+
+fn newest_modification<T: ScanTree>(own: SystemTime, children: &[T]) -> SystemTime {
+    children
+        .iter()
+        .map(ScanTree::last_modified)
+        .fold(own, SystemTime::max)
+}
+
 pub struct AnalyzeConfig {
     pub root_dev: u64,
     pub cache_valid_duration: Duration,
     pub parent_cold_duration: Duration,
     pub apparent: bool,
-    pub sort: bool,
+    /// Order of children, or `None` to keep them in directory order.
+    pub sort: Option<SortBy>,
+    pub options: ScanOptions,
+}
+
+impl AnalyzeConfig {
+    /// Config for a scan without folder cache, sorted by size.
+    fn without_cache(root_dev: u64, apparent: bool, options: &ScanOptions) -> Self {
+        AnalyzeConfig {
+            root_dev,
+            cache_valid_duration: Duration::MAX,
+            parent_cold_duration: Duration::MAX,
+            apparent,
+            sort: Some(SortBy::default()),
+            options: options.clone(),
+        }
+    }
+}
+
+/// Controls of a running scan, independent of what is measured.
+#[derive(Clone, Default)]
+pub struct ScanOptions {
+    /// Counters updated while the scan runs.
+    pub progress: Option<Arc<Progress>>,
+    /// Stops the scan once cancelled. Entries not analyzed by then are left
+    /// out and their ancestors are marked as `incomplete`.
+    pub cancel: Option<CancelToken>,
+    /// Stops the scan like `cancel` once this instant has passed.
+    pub deadline: Option<Instant>,
+    /// Pool running the scan instead of the global rayon pool.
+    pub pool: Option<Arc<ThreadPool>>,
+    /// Limits directory listings and stat calls in flight at once.
+    pub io_limit: Option<Arc<IoLimit>>,
+    /// Limits stat calls per second.
+    pub stat_rate: Option<Arc<RateLimit>>,
+    /// Lists the members of .tar, .tar.gz and .zip files as their children,
+    /// with their uncompressed sizes. Archives still count for their own
+    /// size in totals. Needs the `archives` feature.
+    pub look_into_archives: bool,
+    /// Listings of directories from previous scans, reused for directories
+    /// which have not changed since, and updated with the new ones.
+    pub index: Option<Arc<DirIndex>>,
+}
+
+impl ScanOptions {
+    /// Runs `f` in the dedicated pool if there is one.
+    fn install<R: Send>(&self, f: impl FnOnce() -> R + Send) -> R {
+        match &self.pool {
+            Some(pool) => pool.install(f),
+            None => f(),
+        }
+    }
+
+    fn is_stopped(&self) -> bool {
+        self.cancel.as_ref().is_some_and(CancelToken::is_cancelled)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// Counting semaphore for I/O calls, shared by all threads of a scan and
+/// possibly by several scans.
+#[derive(Debug)]
+pub struct IoLimit {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl IoLimit {
+    pub fn new(max_in_flight: usize) -> Self {
+        IoLimit {
+            available: Mutex::new(max_in_flight.max(1)),
+            released: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> IoPermit<'_> {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        IoPermit { limit: self }
+    }
+}
+
+struct IoPermit<'a> {
+    limit: &'a IoLimit,
+}
+
+impl Drop for IoPermit<'_> {
+    fn drop(&mut self) {
+        *self.limit.available.lock().unwrap() += 1;
+        self.limit.released.notify_one();
+    }
+}
+
+/// Spaces out calls evenly to stay below a number of calls per second.
+#[derive(Debug)]
+pub struct RateLimit {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    pub fn new(per_second: u32) -> Self {
+        RateLimit {
+            interval: Duration::from_secs(1) / per_second.max(1),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Blocks until the next call is allowed.
+    fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+        let now = Instant::now();
+        if slot > now {
+            thread::sleep(slot - now);
+        }
+    }
+}
+
+/// Shared flag to stop a running scan from another thread.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+}
+
+/// Trees and totals of [`DiskItem::from_analyze_targets`].
+pub struct TargetsAnalysis {
+    /// One tree per target, in the order given.
+    pub items: Vec<Result<DiskItem, String>>,
+    /// Indices of targets lying inside another target, left out of the totals.
+    pub nested: Vec<usize>,
+    pub disk_size: u64,
+    pub allocated_size: u64,
+    pub file_count: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SortKey {
+    Size,
+    /// Natural order, so that `file2` comes before `file10`.
+    Name,
+    Count,
+    Mtime,
+}
+
+/// Ordering of children in a tree. Items comparing equal on `key` are
+/// ordered by name, so that the result does not depend on scan timing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SortBy {
+    pub key: SortKey,
+    pub descending: bool,
+}
+
+impl Default for SortBy {
+    fn default() -> Self {
+        SortBy {
+            key: SortKey::Size,
+            descending: true,
+        }
+    }
+}
+
+impl SortBy {
+    pub fn compare(&self, a: &DiskItem, b: &DiskItem) -> Ordering {
+        self.compare_trees(a, b)
+    }
+
+    fn compare_trees<T: ScanTree>(&self, a: &T, b: &T) -> Ordering {
+        let (a_name, b_name) = (a.name(), b.name());
+        let (a_text, b_text) = (a_name.to_string_lossy(), b_name.to_string_lossy());
+        let (a_usage, b_usage) = (a.usage(), b.usage());
+        let ordering = match self.key {
+            SortKey::Size => a_usage.disk_size.cmp(&b_usage.disk_size),
+            SortKey::Name => natural_cmp(&a_text, &b_text).then_with(|| a_name.cmp(b_name)),
+            SortKey::Count => a_usage.file_count.cmp(&b_usage.file_count),
+            SortKey::Mtime => a.last_modified().cmp(&b.last_modified()),
+        };
+        let ordering = if self.descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+        ordering
+            .then_with(|| natural_cmp(&a_text, &b_text))
+            .then_with(|| a_name.cmp(b_name))
+    }
+}
+
+/// Compares names case-insensitively, with runs of digits compared by value.
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_number(&mut a);
+                let y = take_number(&mut b);
+                let ordering = x.len().cmp(&y.len()).then_with(|| x.cmp(&y));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+/// Consumes a run of digits, without leading zeros.
+fn take_number(chars: &mut Peekable<Chars>) -> String {
+    let mut number = String::new();
+    while let Some(c) = chars.next_if(char::is_ascii_digit) {
+        if !(number.is_empty() && c == '0') {
+            number.push(c);
+        }
+    }
+    number
 }
 
 impl DiskItem {
@@ -32,356 +409,977 @@ impl DiskItem {
         root_dev: u64,
         depth_limit: usize,
     ) -> Result<Self, Box<dyn Error>> {
-        #[cfg(windows)]
-        {
-            // Solution for windows compressed files requires path to be absolute, see ffi.rs
-            // Basically it would be triggered only on top most invocation,
-            // and afterwards all path would be absolute. We do it here as it is relatively harmless
-            // but this would allow us fo it only once instead of each invocation of ffi::compressed_size
-            if apparent && !path.is_absolute() {
-                use path_absolutize::*;
-                let absolute_dir = path.absolutize()?;
-                return Self::analyze(
-                    absolute_dir.as_ref(),
-                    apparent,
-                    root_dev,
-                    &DashMap::new(),
-                    depth_limit,
-                );
+        Self::from_analyze_with(
+            path,
+            apparent,
+            root_dev,
+            depth_limit,
+            &ScanOptions::default(),
+        )
+    }
+
+    /// Same as [`DiskItem::from_analyze`], with extra control over the scan.
+    pub fn from_analyze_with(
+        path: &Path,
+        apparent: bool,
+        root_dev: u64,
+        depth_limit: usize,
+        options: &ScanOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        Self::from_analyze_in(&RealFs, path, apparent, root_dev, depth_limit, options)
+    }
+
+    /// Same as [`DiskItem::from_analyze_with`], reading from `backend`.
+    pub fn from_analyze_in<B: FsBackend>(
+        backend: &B,
+        path: &Path,
+        apparent: bool,
+        root_dev: u64,
+        depth_limit: usize,
+        options: &ScanOptions,
+    ) -> Result<Self, Box<dyn Error>> {
+        let config = AnalyzeConfig::without_cache(root_dev, apparent, options);
+        let fileid_map = FileIdMap::new();
+        options
+            .install(|| analyze_root(backend, path, &config, None, depth_limit, &fileid_map, &()))
+            .map_err(|e| -> Box<dyn Error> { e })
+    }
+
+    /// Analyzes several directories concurrently.
+    ///
+    /// Hardlinks shared between targets are counted once in the totals, and
+    /// so are targets lying inside another target.
+    pub fn from_analyze_targets(
+        targets: &[PathBuf],
+        apparent: bool,
+        depth_limit: usize,
+        options: &ScanOptions,
+    ) -> TargetsAnalysis {
+        let infos = targets
+            .iter()
+            .map(|target| match FileInfo::from_path(target, apparent) {
+                Ok(FileInfo::Directory { volume_id, .. }) => Ok(volume_id),
+                Ok(_) => Err(format!("{} is not a directory!", target.display())),
+                Err(e) => Err(format!("{}: {}", target.display(), e)),
+            })
+            .collect::<Vec<_>>();
+        let canonical = targets
+            .iter()
+            .map(|target| target.canonicalize().ok())
+            .collect::<Vec<_>>();
+        let nested = (0..targets.len())
+            .filter(|&i| {
+                (0..targets.len()).any(|j| match (&canonical[i], &canonical[j]) {
+                    // Of two identical targets, the later one is the nested one
+                    (Some(a), Some(b)) if a == b => j < i,
+                    (Some(a), Some(b)) => a.starts_with(b),
+                    _ => false,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        // File ids are only unique within a volume
+        let fileid_maps = infos
+            .iter()
+            .flatten()
+            .map(|&volume_id| (volume_id, FileIdMap::new()))
+            .collect::<HashMap<_, _>>();
+
+        let items = options.install(|| {
+            targets
+                .par_iter()
+                .zip(infos)
+                .enumerate()
+                .map(|(i, (target, info))| {
+                    let volume_id = info?;
+                    let nested_map = FileIdMap::new();
+                    let fileid_map = if nested.contains(&i) {
+                        &nested_map
+                    } else {
+                        &fileid_maps[&volume_id]
+                    };
+                    let config = AnalyzeConfig::without_cache(volume_id, apparent, options);
+                    analyze_root::<DiskItem, _>(
+                        &RealFs,
+                        target,
+                        &config,
+                        None,
+                        depth_limit,
+                        fileid_map,
+                        &(),
+                    )
+                    .map_err(|e| format!("{}: {}", target.display(), e))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let mut total = Usage::default();
+        for (i, item) in items.iter().enumerate() {
+            if let (Ok(item), false) = (item, nested.contains(&i)) {
+                total += item.usage();
             }
         }
-        Self::analyze(path, apparent, root_dev, &DashMap::new(), depth_limit)
+        for (_, links) in fileid_maps.into_values().flatten() {
+            total = total.without(links.repeated);
+        }
+
+        TargetsAnalysis {
+            items,
+            nested,
+            disk_size: total.disk_size,
+            allocated_size: total.allocated_size,
+            file_count: total.file_count,
+        }
     }
 
     pub fn with_cache(
         path: &Path,
         config: AnalyzeConfig,
         depth_limit: usize,
-        cache: &DashMap<u64, (SystemTime, u64)>,
-        cache_used: &DashSet<u64>,
+        cache: &Cache,
     ) -> Result<Self, Box<dyn Error>> {
-        #[cfg(windows)]
-        {
-            // Solution for windows compressed files requires path to be absolute, see ffi.rs
-            // Basically it would be triggered only on top most invocation,
-            // and afterwards all path would be absolute. We do it here as it is relatively harmless
-            // but this would allow us fo it only once instead of each invocation of ffi::compressed_size
-            if apparent && !path.is_absolute() {
-                use path_absolutize::*;
-                let absolute_dir = path.absolutize()?;
-
-                return Self::analyze_with_folder_cache(
-                    absolute_dir.as_ref(),
+        Self::with_cache_in(&RealFs, path, config, depth_limit, cache)
+    }
+
+    /// Same as [`DiskItem::with_cache`], reading from `backend`.
+    pub fn with_cache_in<B: FsBackend>(
+        backend: &B,
+        path: &Path,
+        config: AnalyzeConfig,
+        depth_limit: usize,
+        cache: &Cache,
+    ) -> Result<Self, Box<dyn Error>> {
+        config
+            .options
+            .install(|| {
+                analyze_root(
+                    backend,
+                    path,
                     &config,
+                    Some(cache),
                     depth_limit,
-                    &DashMap::new(),
-                    cache,
-                    cache_used,
-                );
-            }
-        }
+                    &FileIdMap::new(),
+                    &(),
+                )
+            })
+            .map_err(|e| -> Box<dyn Error> { e })
+    }
 
-        Self::analyze_with_folder_cache(
-            path,
-            &config,
-            depth_limit,
-            &DashMap::new(),
-            cache,
-            cache_used,
-        )
+    pub fn rescan_child(
+        &mut self,
+        path: &Path,
+        name: &OsStr,
+        config: AnalyzeConfig,
+        depth_limit: usize,
+        cache: &Cache,
+    ) -> Result<(), Box<dyn Error>> {
+        self.rescan_child_in(&RealFs, path, name, config, depth_limit, cache)
     }
 
-    fn analyze_with_folder_cache(
+    /// Scans the child `name` of the item scanned from `path` again, taking
+    /// the sizes of cold folders from `cache` as [`DiskItem::with_cache`] does
+    /// with `depth_limit` for the item. The child replaces the one of the same
+    /// name, and the sizes of this item are updated.
+    ///
+    /// Hard links between the child and its siblings may be counted twice.
+    pub fn rescan_child_in<B: FsBackend>(
+        &mut self,
+        backend: &B,
         path: &Path,
-        config: &AnalyzeConfig,
+        name: &OsStr,
+        config: AnalyzeConfig,
         depth_limit: usize,
-        fileid_map: &DashMap<u64, u64>,
-        cache: &DashMap<u64, (SystemTime, u64)>,
-        cache_used: &DashSet<u64>,
-    ) -> Result<Self, Box<dyn Error>> {
-        let name = path
-            .file_name()
-            .unwrap_or_else(|| OsStr::new("."))
-            .to_string_lossy()
-            .to_string();
+        cache: &Cache,
+    ) -> Result<(), Box<dyn Error>> {
+        let child: DiskItem = config
+            .options
+            .install(|| {
+                analyze_below(
+                    backend,
+                    path,
+                    &path.join(name),
+                    &config,
+                    Some(cache),
+                    depth_limit.saturating_sub(1),
+                    &FileIdMap::new(),
+                    &(),
+                )
+            })
+            .map_err(|e| -> Box<dyn Error> { e })?;
+        self.replace_child(child);
+        if let (Some(sort), Some(children)) = (config.sort, &mut self.children) {
+            children.sort_unstable_by(|a, b| sort.compare(a, b));
+        }
+        Ok(())
+    }
 
-        let file_info = FileInfo::from_path(path, config.apparent)?;
+    /// Puts `child` in place of the child of the same name, or adds it, and
+    /// updates the sizes of this item.
+    fn replace_child(&mut self, child: DiskItem) {
+        self.disk_size += child.disk_size;
+        self.allocated_size += child.allocated_size;
+        self.file_count += child.file_count;
+        self.last_modified = self.last_modified.max(child.last_modified);
+        self.incomplete |= child.incomplete;
+        let children = self.children.get_or_insert_with(Vec::new);
+        let old = match children.iter_mut().find(|item| item.name == child.name) {
+            Some(item) => std::mem::replace(item, child),
+            None => {
+                children.push(child);
+                return;
+            }
+        };
+        self.disk_size = self.disk_size.saturating_sub(old.disk_size);
+        self.allocated_size = self.allocated_size.saturating_sub(old.allocated_size);
+        self.file_count = self.file_count.saturating_sub(old.file_count);
+    }
 
-        match file_info {
-            FileInfo::Directory {
-                volume_id,
-                file_id,
-                last_modified,
-            } => {
-                if volume_id != config.root_dev {
-                    return Err("Filesystem boundary crossed".into());
-                }
+    /// Reorders children of this item and all its descendants.
+    pub fn sort(&mut self, sort: SortBy) {
+        if let Some(children) = &mut self.children {
+            children.sort_unstable_by(|a, b| sort.compare(a, b));
+            for child in children {
+                child.sort(sort);
+            }
+        }
+    }
 
-                let sub_entries = fs::read_dir(path)?
-                    .filter_map(Result::ok)
-                    .collect::<Vec<_>>();
-
-                let now = SystemTime::now();
-                let cache_valid;
-
-                let (mut sub_items, disk_size) = if depth_limit > 0 {
-                    cache_valid = now.duration_since(last_modified).unwrap_or_default()
-                        > config.parent_cold_duration;
-                    if let Some(last_info) = cache.get_mut(&file_id) {
-                        if cache_valid
-                            && last_info.0 != SystemTime::UNIX_EPOCH
-                            && last_info.0 == last_modified
-                        {
-                            info!("file {} loaded cached size {}", path.to_string_lossy(), last_info.1);
-                            cache_used.insert(file_id);
-                            return Ok(DiskItem {
-                                name,
-                                disk_size: last_info.1,
-                                children: None,
-                            });
-                        }
-                    }
-                    let my_fileid_map = DashMap::new();
-                    let sub_items = sub_entries
-                        .par_iter()
-                        .filter_map(|entry| {
-                            Self::analyze_with_folder_cache(
-                                &entry.path(),
-                                config,
-                                depth_limit - 1,
-                                &my_fileid_map,
-                                cache,
-                                cache_used,
-                            )
-                            .ok()
-                        })
-                        .collect::<Vec<_>>();
-                    let disk_size: u64 = sub_items.iter().map(|di| di.disk_size).sum();
-                    let repeated_size: u64 = my_fileid_map
-                        .into_iter()
-                        .map(|(k, v)| {
-                            fileid_map.entry(k).and_modify(|x| *x += v).or_insert(0);
-                            v
-                        })
-                        .sum();
-                    (sub_items, disk_size - repeated_size)
-                } else {
-                    cache_valid = now.duration_since(last_modified).unwrap_or_default()
-                        > config.cache_valid_duration;
-                    if let Some(last_info) = cache.get_mut(&file_id) {
-                        if cache_valid
-                            && last_info.0 != SystemTime::UNIX_EPOCH
-                            && last_info.0 == last_modified
-                        {
-                            info!("file {} loaded cached size {}", path.to_string_lossy(), last_info.1);
-                            cache_used.insert(file_id);
-                            return Ok(DiskItem {
-                                name,
-                                disk_size: last_info.1,
-                                children: None,
-                            });
-                        }
-                    }
-                    let sub_items = sub_entries
-                        .par_iter()
-                        .filter_map(|entry| {
-                            Self::analyze_with_folder_cache(
-                                &entry.path(),
-                                config,
-                                0,
-                                fileid_map,
-                                cache,
-                                cache_used,
-                            )
-                            .ok()
-                        })
-                        .collect::<Vec<_>>();
-                    let disk_size = sub_items.iter().map(|di| di.disk_size).sum();
-                    (sub_items, disk_size)
-                };
+    /// Item at `path`, relative to this one.
+    pub fn get(&self, path: &Path) -> Option<&DiskItem> {
+        let mut item = self;
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(name) => item = item.child(name)?,
+                _ => return None,
+            }
+        }
+        Some(item)
+    }
 
-                if cache_valid {
-                    cache
-                        .entry(file_id)
-                        .and_modify(|x| *x = (last_modified, disk_size))
-                        .or_insert_with(|| (last_modified, disk_size));
-                    cache_used.insert(file_id);
-                    info!("cache added for {} ({})", file_id, path.to_string_lossy());
+    /// Items from the one at `path`, relative to this one, up to this one.
+    pub fn ancestors(&self, path: &Path) -> Option<Vec<&DiskItem>> {
+        let mut items = vec![self];
+        for component in path.components() {
+            match component {
+                Component::CurDir => {}
+                Component::Normal(name) => {
+                    let child = items.last().unwrap().child(name)?;
+                    items.push(child);
                 }
-                Ok(DiskItem {
-                    name,
-                    disk_size,
-                    children: if depth_limit > 0 {
-                        if config.sort {
-                            sub_items
-                                .sort_unstable_by(|a, b| a.disk_size.cmp(&b.disk_size).reverse());
-                        }
-                        Some(sub_items)
-                    } else {
-                        None
-                    },
-                })
+                _ => return None,
             }
-            FileInfo::File {
-                size,
-                file_id: inode,
-                ..
-            } => {
-                fileid_map
-                    .entry(inode)
-                    .and_modify(|x| *x += size)
-                    .or_insert(0);
-                Ok(DiskItem {
-                    name,
-                    disk_size: size,
-                    children: None,
-                })
+        }
+        items.reverse();
+        Some(items)
+    }
+
+    fn child(&self, name: &OsStr) -> Option<&DiskItem> {
+        self.children
+            .iter()
+            .flatten()
+            .find(|child| child.name == name)
+    }
+
+    /// Walks this item and its descendants, parents before their children.
+    /// Paths start from `path`, the one of this item.
+    pub fn depth_first(&self, path: &Path) -> DepthFirst<'_> {
+        DepthFirst {
+            stack: vec![TreeEntry::root(self, path)],
+        }
+    }
+
+    /// Walks this item and its descendants, level by level.
+    /// Paths start from `path`, the one of this item.
+    pub fn breadth_first(&self, path: &Path) -> BreadthFirst<'_> {
+        BreadthFirst {
+            queue: VecDeque::from(vec![TreeEntry::root(self, path)]),
+        }
+    }
+
+    /// First entry in depth first order matching `predicate`.
+    pub fn find<P>(&self, path: &Path, mut predicate: P) -> Option<TreeEntry<'_>>
+    where
+        P: FnMut(&TreeEntry) -> bool,
+    {
+        self.depth_first(path).find(|entry| predicate(entry))
+    }
+}
+
+/// An item met while walking a tree.
+pub struct TreeEntry<'a> {
+    pub path: PathBuf,
+    /// Distance from the item the walk started at.
+    pub depth: usize,
+    pub item: &'a DiskItem,
+}
+
+impl<'a> TreeEntry<'a> {
+    fn root(item: &'a DiskItem, path: &Path) -> Self {
+        TreeEntry {
+            path: path.to_path_buf(),
+            depth: 0,
+            item,
+        }
+    }
+
+    fn children(&self) -> impl DoubleEndedIterator<Item = TreeEntry<'a>> + '_ {
+        self.item
+            .children
+            .iter()
+            .flatten()
+            .map(move |child| TreeEntry {
+                path: self.path.join(&child.name),
+                depth: self.depth + 1,
+                item: child,
+            })
+    }
+}
+
+pub struct DepthFirst<'a> {
+    stack: Vec<TreeEntry<'a>>,
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = TreeEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.stack.pop()?;
+        self.stack.extend(entry.children().rev());
+        Some(entry)
+    }
+}
+
+pub struct BreadthFirst<'a> {
+    queue: VecDeque<TreeEntry<'a>>,
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = TreeEntry<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.queue.pop_front()?;
+        self.queue.extend(entry.children());
+        Some(entry)
+    }
+}
+
+/// Values of a scanned entry, before it is stored in a tree.
+pub(crate) struct Node {
+    name: OsString,
+    disk_size: u64,
+    allocated_size: u64,
+    file_count: u64,
+    last_modified: SystemTime,
+    incomplete: bool,
+}
+
+/// Tree representations a scan can build.
+pub(crate) trait ScanTree: Sized + Send {
+    /// Storage shared by all items of a scan.
+    type Store: Sync;
+
+    /// Builds an item, with children if they are kept at its depth.
+    fn from_node(store: &Self::Store, node: Node, children: Option<Vec<Self>>) -> Self;
+    fn name(&self) -> &OsStr;
+    fn usage(&self) -> Usage;
+    fn last_modified(&self) -> SystemTime;
+    fn incomplete(&self) -> bool;
+}
+
+impl ScanTree for DiskItem {
+    type Store = ();
+
+    fn from_node(_: &(), node: Node, children: Option<Vec<Self>>) -> Self {
+        DiskItem {
+            name: node.name,
+            disk_size: node.disk_size,
+            allocated_size: node.allocated_size,
+            file_count: node.file_count,
+            last_modified: node.last_modified,
+            incomplete: node.incomplete,
+            children,
+        }
+    }
+
+    fn name(&self) -> &OsStr {
+        &self.name
+    }
+
+    fn usage(&self) -> Usage {
+        Usage {
+            disk_size: self.disk_size,
+            allocated_size: self.allocated_size,
+            file_count: self.file_count,
+        }
+    }
+
+    fn last_modified(&self) -> SystemTime {
+        self.last_modified
+    }
+
+    fn incomplete(&self) -> bool {
+        self.incomplete
+    }
+}
+
+/// What all entries of a scan share.
+struct Scan<'a, T: ScanTree, B: FsBackend> {
+    backend: &'a B,
+    config: &'a AnalyzeConfig,
+    cache: Option<&'a Cache>,
+    store: &'a T::Store,
+    /// Path the scan started from.
+    root: &'a Path,
+}
+
+impl<T: ScanTree, B: FsBackend> Scan<'_, T, B> {
+    /// Path of an entry relative to the root of the scan.
+    fn relative<'p>(&self, path: &'p Path) -> &'p Path {
+        path.strip_prefix(self.root).unwrap_or(path)
+    }
+
+    /// Runs a stat call within the configured limits.
+    fn stat(&self, stat: impl FnOnce() -> io::Result<FileInfo>) -> io::Result<FileInfo> {
+        if let Some(stat_rate) = &self.config.options.stat_rate {
+            stat_rate.wait();
+        }
+        let _permit = self
+            .config
+            .options
+            .io_limit
+            .as_deref()
+            .map(IoLimit::acquire);
+        stat()
+    }
+}
+
+fn analyze_root<T: ScanTree, B: FsBackend>(
+    backend: &B,
+    path: &Path,
+    config: &AnalyzeConfig,
+    cache: Option<&Cache>,
+    depth_limit: usize,
+    fileid_map: &FileIdMap,
+    store: &T::Store,
+) -> Result<T, ScanError> {
+    analyze_below(
+        backend,
+        path,
+        path,
+        config,
+        cache,
+        depth_limit,
+        fileid_map,
+        store,
+    )
+}
+
+/// Same as [`analyze_root`] for `path` below `root`, the path cached folders
+/// are relative to.
+#[allow(clippy::too_many_arguments)]
+fn analyze_below<T: ScanTree, B: FsBackend>(
+    backend: &B,
+    root: &Path,
+    path: &Path,
+    config: &AnalyzeConfig,
+    cache: Option<&Cache>,
+    depth_limit: usize,
+    fileid_map: &FileIdMap,
+    store: &T::Store,
+) -> Result<T, ScanError> {
+    #[cfg(windows)]
+    let (absolute_root, absolute_dir);
+    #[cfg(windows)]
+    let (root, path) = {
+        // Solution for windows compressed files requires path to be absolute, see ffi.rs
+        // Basically it would be triggered only on top most invocation,
+        // and afterwards all path would be absolute. We do it here as it is relatively harmless
+        // but this would allow us fo it only once instead of each invocation of ffi::compressed_size
+        if config.apparent && !path.is_absolute() {
+            use path_absolutize::*;
+            absolute_root = root.absolutize()?;
+            absolute_dir = path.absolutize()?;
+            (absolute_root.as_ref(), absolute_dir.as_ref())
+        } else {
+            (root, path)
+        }
+    };
+
+    let scan = Scan {
+        backend,
+        config,
+        cache,
+        store,
+        root,
+    };
+    match scan.stat(|| backend.stat(path, config.apparent))? {
+        FileInfo::Directory {
+            volume_id,
+            file_id,
+            last_modified,
+            changed,
+        } => {
+            if volume_id != config.root_dev {
+                return Err("Filesystem boundary crossed".into());
+            }
+            let meta = DirMeta {
+                file_id,
+                last_modified,
+                changed,
+            };
+            let open = || backend.open(path);
+            analyze_dir(&scan, path, meta, open, depth_limit, fileid_map)
+        }
+        FileInfo::File {
+            size,
+            allocated_size,
+            file_id,
+            links,
+            last_modified,
+            ..
+        } => {
+            let usage = Usage {
+                disk_size: size,
+                allocated_size,
+                file_count: 1,
+            };
+            let name = entry_name(path);
+            let node = analyze_file(
+                &scan,
+                name,
+                usage,
+                file_id,
+                links,
+                last_modified,
+                fileid_map,
+            );
+            Ok(T::from_node(store, node, None))
+        }
+    }
+}
+
+fn entry_name(path: &Path) -> OsString {
+    path.file_name()
+        .unwrap_or_else(|| OsStr::new("."))
+        .to_os_string()
+}
+
+fn analyze_entry<T: ScanTree, B: FsBackend>(
+    scan: &Scan<T, B>,
+    parent: &B::Dir,
+    parent_path: &Path,
+    entry: &DirEntry,
+    depth_limit: usize,
+    fileid_map: &FileIdMap,
+) -> Result<T, ScanError> {
+    let name = entry.name.clone();
+    if entry.kind == EntryKind::Special {
+        // Nothing worth a stat call, they are counted as empty files
+        let usage = Usage {
+            file_count: 1,
+            ..Usage::default()
+        };
+        let epoch = SystemTime::UNIX_EPOCH;
+        let node = analyze_file(scan, name, usage, 0, 1, epoch, fileid_map);
+        return Ok(T::from_node(scan.store, node, None));
+    }
+
+    match scan.stat(|| scan.backend.stat_at(parent, entry, scan.config.apparent))? {
+        FileInfo::Directory {
+            volume_id,
+            file_id,
+            last_modified,
+            changed,
+        } => {
+            if volume_id != scan.config.root_dev {
+                return Err("Filesystem boundary crossed".into());
             }
+            let path = parent_path.join(name);
+            let meta = DirMeta {
+                file_id,
+                last_modified,
+                changed,
+            };
+            let open = || scan.backend.open_at(parent, entry);
+            analyze_dir(scan, &path, meta, open, depth_limit, fileid_map)
+        }
+        FileInfo::File {
+            size,
+            allocated_size,
+            file_id,
+            links,
+            last_modified,
+            ..
+        } => {
+            let usage = Usage {
+                disk_size: size,
+                allocated_size,
+                file_count: 1,
+            };
+            let node = analyze_file(scan, name, usage, file_id, links, last_modified, fileid_map);
+            let members =
+                archive_members(scan, parent, parent_path, entry, last_modified, depth_limit);
+            Ok(T::from_node(scan.store, node, members))
         }
     }
+}
 
-    fn analyze(
-        path: &Path,
-        apparent: bool,
-        root_dev: u64,
-        fileid_map: &DashMap<u64, u64>,
-        depth_limit: usize,
-    ) -> Result<Self, Box<dyn Error>> {
-        let name = path
-            .file_name()
-            .unwrap_or_else(|| OsStr::new("."))
-            .to_string_lossy()
-            .to_string();
-
-        let file_info = FileInfo::from_path(path, apparent)?;
-
-        match file_info {
-            FileInfo::Directory { volume_id, .. } => {
-                if volume_id != root_dev {
-                    return Err("Filesystem boundary crossed".into());
-                }
+fn analyze_file<T: ScanTree, B: FsBackend>(
+    scan: &Scan<T, B>,
+    name: OsString,
+    usage: Usage,
+    file_id: u64,
+    links: u64,
+    last_modified: SystemTime,
+    fileid_map: &FileIdMap,
+) -> Node {
+    if let Some(progress) = scan.config.options.progress.as_deref() {
+        progress.add_file(usage.disk_size);
+    }
+    // Files with a single link cannot be counted twice, keeping them out
+    // of the map saves an entry per file on large trees
+    if links > 1 {
+        add_link(fileid_map, file_id, usage);
+    }
+    Node {
+        name,
+        disk_size: usage.disk_size,
+        allocated_size: usage.allocated_size,
+        file_count: usage.file_count,
+        last_modified,
+        incomplete: false,
+    }
+}
 
-                let sub_entries = fs::read_dir(path)?
-                    .filter_map(Result::ok)
-                    .collect::<Vec<_>>();
-
-                let (mut sub_items, disk_size) = if depth_limit > 0 {
-                    let my_fileid_map = DashMap::new();
-                    let sub_items = sub_entries
-                        .par_iter()
-                        .filter_map(|entry| {
-                            Self::analyze(
-                                &entry.path(),
-                                apparent,
-                                root_dev,
-                                &my_fileid_map,
-                                depth_limit - 1,
-                            )
-                            .ok()
-                        })
-                        .collect::<Vec<_>>();
-                    let disk_size: u64 = sub_items.iter().map(|di| di.disk_size).sum();
-                    let repeated_size: u64 = my_fileid_map
-                        .into_iter()
-                        .map(|(k, v)| {
-                            fileid_map.entry(k).and_modify(|x| *x += v).or_insert(0);
-                            v
-                        })
-                        .sum();
-                    (sub_items, disk_size - repeated_size)
-                } else {
-                    let sub_items = sub_entries
-                        .par_iter()
-                        .filter_map(|entry| {
-                            Self::analyze(&entry.path(), apparent, root_dev, fileid_map, 0).ok()
-                        })
-                        .collect::<Vec<_>>();
-                    let disk_size = sub_items.iter().map(|di| di.disk_size).sum();
-                    (sub_items, disk_size)
-                };
+/// Whether `error` comes from the process or the system running out of file
+/// descriptors, which deep trees scanned by many threads can bring about.
+fn is_out_of_fds(error: &io::Error) -> bool {
+    #[cfg(unix)]
+    let codes = [libc::EMFILE, libc::ENFILE];
+    #[cfg(windows)]
+    let codes = [windows_sys::Win32::Foundation::ERROR_TOO_MANY_OPEN_FILES as i32];
+    error
+        .raw_os_error()
+        .is_some_and(|code| codes.contains(&code))
+}
 
-                sub_items.sort_unstable_by(|a, b| a.disk_size.cmp(&b.disk_size).reverse());
+/// Members of `entry` as its children, if it is an archive to look into.
+#[cfg(feature = "archives")]
+fn archive_members<T: ScanTree, B: FsBackend>(
+    scan: &Scan<T, B>,
+    parent: &B::Dir,
+    parent_path: &Path,
+    entry: &DirEntry,
+    last_modified: SystemTime,
+    depth_limit: usize,
+) -> Option<Vec<T>> {
+    if !scan.config.options.look_into_archives || depth_limit == 0 {
+        return None;
+    }
+    let format = archive::Format::from_name(&entry.name)?;
+    let _permit = scan
+        .config
+        .options
+        .io_limit
+        .as_deref()
+        .map(IoLimit::acquire);
+    let members = scan.backend.open_file(parent, entry).and_then(|file| {
+        let sort = scan.config.sort;
+        archive::members(
+            scan.store,
+            format,
+            file,
+            last_modified,
+            depth_limit - 1,
+            sort,
+        )
+    });
+    match members {
+        Ok(members) => Some(members),
+        Err(e) => {
+            let path = parent_path.join(&entry.name);
+            info!("cannot read archive {}: {}", path.to_string_lossy(), e);
+            None
+        }
+    }
+}
 
-                Ok(DiskItem {
-                    name,
-                    disk_size,
-                    children: if depth_limit > 0 {
-                        Some(sub_items)
-                    } else {
-                        None
-                    },
-                })
+#[cfg(not(feature = "archives"))]
+fn archive_members<T: ScanTree, B: FsBackend>(
+    _: &Scan<T, B>,
+    _: &B::Dir,
+    _: &Path,
+    _: &DirEntry,
+    _: SystemTime,
+    _: usize,
+) -> Option<Vec<T>> {
+    None
+}
+
+/// What the stat of a directory tells about it.
+#[derive(Clone, Copy)]
+struct DirMeta {
+    file_id: u64,
+    last_modified: SystemTime,
+    changed: SystemTime,
+}
+
+fn analyze_dir<T: ScanTree, B: FsBackend>(
+    scan: &Scan<T, B>,
+    path: &Path,
+    meta: DirMeta,
+    open: impl FnOnce() -> io::Result<B::Dir>,
+    depth_limit: usize,
+    fileid_map: &FileIdMap,
+) -> Result<T, ScanError> {
+    let DirMeta {
+        file_id,
+        last_modified,
+        changed,
+    } = meta;
+    let config = scan.config;
+    let name = entry_name(path);
+    let progress = config.options.progress.as_deref();
+    if let Some(progress) = progress {
+        progress.add_dir(path);
+    }
+
+    let permit = config.options.io_limit.as_deref().map(IoLimit::acquire);
+    let dir = open()?;
+
+    // Key of a cold folder in the cache, which gets its size once scanned
+    let cache_key = match scan.cache {
+        Some(cache) => {
+            let cold_duration = if depth_limit > 0 {
+                config.parent_cold_duration
+            } else {
+                config.cache_valid_duration
+            };
+            let cache_valid = SystemTime::now()
+                .duration_since(last_modified)
+                .unwrap_or_default()
+                > cold_duration;
+            if cache_valid {
+                let generation = scan
+                    .backend
+                    .generation(&dir)
+                    .map_or(Generation::Changed(changed), Generation::Inode);
+                let key = CacheKey {
+                    volume_id: config.root_dev,
+                    file_id,
+                    generation,
+                };
+                if let Some(disk_size) = cache.lookup(&key, last_modified, scan.relative(path)) {
+                    info!(
+                        "file {} loaded cached size {}",
+                        path.to_string_lossy(),
+                        disk_size
+                    );
+                    if let Some(progress) = progress {
+                        progress.add_bytes(disk_size);
+                    }
+                    let node = Node {
+                        name,
+                        disk_size,
+                        allocated_size: 0,
+                        file_count: 0,
+                        last_modified,
+                        incomplete: false,
+                    };
+                    return Ok(T::from_node(scan.store, node, None));
+                }
+                Some(key)
+            } else {
+                None
             }
-            FileInfo::File {
-                size,
-                file_id: inode,
-                ..
-            } => {
-                fileid_map
-                    .entry(inode)
-                    .and_modify(|x| *x += size)
-                    .or_insert(0);
-                Ok(DiskItem {
-                    name,
-                    disk_size: size,
-                    children: None,
-                })
+        }
+        None => None,
+    };
+
+    let index = config.options.index.as_deref();
+    let index_key = (config.root_dev, file_id);
+    let indexed = index.and_then(|index| index.entries(index_key, last_modified, changed));
+    // Time of the listing, unless the entries came from the index
+    let mut listed_at = None;
+    let entries = match indexed {
+        Some(entries) => entries,
+        None => {
+            listed_at = Some(SystemTime::now());
+            let mut entries = Vec::new();
+            for entry in scan.backend.entries(&dir)? {
+                match entry {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => {
+                        if let Some(progress) = progress {
+                            progress.add_error(e.kind());
+                        }
+                        // A partial listing must not be reused
+                        listed_at = None;
+                    }
+                }
             }
+            entries
         }
+    };
+    drop(permit);
+
+    let skipped = AtomicBool::new(false);
+    let analyze_entries = |fileid_map: &FileIdMap, depth_limit: usize| {
+        entries
+            .par_iter()
+            .filter_map(|entry| {
+                if config.options.is_stopped() {
+                    skipped.store(true, atomic::Ordering::Relaxed);
+                    return None;
+                }
+                match analyze_entry(scan, &dir, path, entry, depth_limit, fileid_map) {
+                    Ok(item) => Some(item),
+                    Err(e) => {
+                        let io_error = e.downcast_ref::<io::Error>();
+                        if let (Some(progress), Some(io_error)) = (progress, io_error) {
+                            progress.add_error(io_error.kind());
+                        }
+                        // Unlike an unreadable entry, this one may hold anything
+                        if io_error.is_some_and(is_out_of_fds) {
+                            skipped.store(true, atomic::Ordering::Relaxed);
+                        }
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let (mut sub_items, usage) = if depth_limit > 0 {
+        let my_fileid_map = FileIdMap::new();
+        let sub_items = analyze_entries(&my_fileid_map, depth_limit - 1);
+        let repeated = merge_links(my_fileid_map, fileid_map);
+        let total = Usage::total(&sub_items);
+        (sub_items, total.without(repeated))
+    } else {
+        let sub_items = analyze_entries(fileid_map, 0);
+        let total = Usage::total(&sub_items);
+        (sub_items, total)
+    };
+
+    let disk_size = usage.disk_size;
+    let incomplete = skipped.into_inner() || sub_items.iter().any(ScanTree::incomplete);
+    if let (Some(cache), Some(key), false) = (scan.cache, cache_key, incomplete) {
+        let size = CachedSize {
+            last_modified,
+            disk_size,
+            path: scan.relative(path).to_path_buf(),
+            scanned: SystemTime::now(),
+        };
+        cache.store(key, size);
+        info!("cache added for {} ({})", file_id, path.to_string_lossy());
+    }
+    if let (Some(index), Some(listed_at), false) = (index, listed_at, incomplete) {
+        let indexed = IndexedDir {
+            last_modified,
+            changed,
+            entries,
+        };
+        index.insert(index_key, indexed, listed_at);
     }
+
+    let node = Node {
+        name,
+        disk_size,
+        allocated_size: usage.allocated_size,
+        file_count: usage.file_count,
+        last_modified: newest_modification(last_modified, &sub_items),
+        incomplete,
+    };
+    let children = if depth_limit > 0 {
+        if let Some(sort) = config.sort {
+            sub_items.sort_unstable_by(|a, b| sort.compare_trees(a, b));
+        }
+        Some(sub_items)
+    } else {
+        None
+    };
+    Ok(T::from_node(scan.store, node, children))
 }
 
 pub enum FileInfo {
     File {
         size: u64,
+        /// Allocation size, regardless of the `apparent` flag. On Windows this
+        /// is only queried when `apparent` is set and equals `size` otherwise.
+        allocated_size: u64,
         volume_id: u64,
         file_id: u64,
+        /// Number of hard links to the file.
+        links: u64,
+        last_modified: SystemTime,
     },
     Directory {
         volume_id: u64,
         file_id: u64,
         last_modified: SystemTime,
+        /// Last change of the directory or its metadata, which unlike
+        /// `last_modified` cannot be set back.
+        changed: SystemTime,
     },
 }
 
 impl FileInfo {
-    #[cfg(unix)]
     pub fn from_path(path: &Path, apparent: bool) -> Result<Self, Box<dyn Error>> {
+        Ok(Self::stat(path, apparent)?)
+    }
+
+    #[cfg(unix)]
+    fn stat(path: &Path, apparent: bool) -> io::Result<Self> {
         use std::os::unix::fs::MetadataExt;
 
         let md = path.symlink_metadata()?;
+        let last_modified = md.modified().unwrap_or(SystemTime::UNIX_EPOCH);
         if md.is_dir() {
+            // A status change time before 1970 is as good as unknown
+            let changed = u64::try_from(md.ctime()).map_or(SystemTime::UNIX_EPOCH, |secs| {
+                SystemTime::UNIX_EPOCH + Duration::new(secs, md.ctime_nsec() as u32)
+            });
             Ok(FileInfo::Directory {
                 volume_id: md.dev(),
                 file_id: md.ino(),
-                last_modified: md.modified().unwrap_or(SystemTime::UNIX_EPOCH),
+                last_modified,
+                changed,
             })
         } else {
-            let size = if apparent {
-                md.blocks() * 512
-            } else {
-                md.len()
-            };
+            let allocated_size = md.blocks() * 512;
+            let size = if apparent { allocated_size } else { md.len() };
             Ok(FileInfo::File {
                 size,
+                allocated_size,
                 volume_id: md.dev(),
                 file_id: md.ino(),
+                links: md.nlink(),
+                last_modified,
             })
         }
     }
 
     #[cfg(windows)]
-    pub fn from_path(path: &Path, apparent: bool) -> Result<Self, Box<dyn Error>> {
+    fn stat(path: &Path, apparent: bool) -> io::Result<Self> {
         use winapi_util::{file, Handle};
         const FILE_ATTRIBUTE_DIRECTORY: u64 = 0x10;
 
         let h = Handle::from_path_any(path)?;
         let md = file::information(h)?;
+        let last_modified = md
+            .last_write_time()
+            .map(ffi::filetime_to_system_time)
+            .unwrap_or(SystemTime::UNIX_EPOCH);
 
         if md.file_attributes() & FILE_ATTRIBUTE_DIRECTORY != 0 {
+            // Windows has no status change time, modifications stand for it
             Ok(FileInfo::Directory {
                 volume_id: md.volume_serial_number(),
                 file_id: md.file_index(),
+                last_modified,
+                changed: last_modified,
             })
         } else {
             let size = if apparent {
@@ -391,12 +1389,17 @@ impl FileInfo {
             };
             Ok(FileInfo::File {
                 size,
+                allocated_size: size,
                 volume_id: md.volume_serial_number(),
                 file_id: md.file_index(),
+                links: md.number_of_links(),
+                last_modified,
             })
         }
     }
 }
 
 #[cfg(test)]
+// The first tests, kept as they were written, predate these lints
+#[allow(clippy::needless_borrow, clippy::unused_io_amount)]
 mod tests;
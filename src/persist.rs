@@ -0,0 +1,104 @@
+//! Files that readers never see half written.
+
+use std::convert::{TryFrom, TryInto};
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+
+/// Replaces the file at `path` with `contents`, through a temporary file in
+/// the same directory renamed over it. A crash or an error along the way
+/// leaves the previous file untouched, and once this returns the new file
+/// survives a crash.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(format!(".{}.tmp", std::process::id()));
+    let temp_path = path.with_file_name(temp_name);
+
+    let result = File::create(&temp_path).and_then(|mut file| {
+        file.write_all(contents)?;
+        // The data must be on disk before the rename makes it visible
+        file.sync_all()
+    });
+    let result = result.and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+    // The rename itself is only durable once the directory is on disk
+    sync_dir(path.parent().unwrap_or(Path::new("")))
+}
+
+#[cfg(unix)]
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    File::open(dir)?.sync_all()
+}
+
+/// Directories cannot be opened as files on Windows, where renames are
+/// journaled by NTFS instead.
+#[cfg(not(unix))]
+fn sync_dir(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+/// Length of the header of [`write_with_header`].
+const HEADER_LEN: usize = 8 + 4 + 8 + 4;
+
+/// Replaces the file at `path` like [`write_atomic`], with `payload` after a
+/// header: `magic`, then as little endian integers `version` (`u32`), the
+/// length of the payload (`u64`) and its CRC-32 (`u32`).
+pub fn write_with_header(
+    path: &Path,
+    magic: &[u8; 8],
+    version: u32,
+    payload: &[u8],
+) -> io::Result<()> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(magic);
+    bytes.extend_from_slice(&version.to_le_bytes());
+    bytes.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    bytes.extend_from_slice(payload);
+    write_atomic(path, &bytes)
+}
+
+/// Version and payload of contents written by [`write_with_header`], once
+/// checked.
+pub fn read_header<'a>(
+    bytes: &'a [u8],
+    magic: &[u8; 8],
+) -> Result<(u32, &'a [u8]), Box<dyn Error>> {
+    if !bytes.starts_with(magic) {
+        return Err("unknown file format".into());
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err("truncated header".into());
+    }
+    let field = |start: usize, len: usize| &bytes[start..start + len];
+    let version = u32::from_le_bytes(field(8, 4).try_into()?);
+    let len = u64::from_le_bytes(field(12, 8).try_into()?);
+    let checksum = u32::from_le_bytes(field(20, 4).try_into()?);
+    let payload = &bytes[HEADER_LEN..];
+    if usize::try_from(len).ok() != Some(payload.len()) {
+        return Err(format!(
+            "truncated file: {} bytes of payload, expected {}",
+            payload.len(),
+            len
+        )
+        .into());
+    }
+    if crc32fast::hash(payload) != checksum {
+        return Err("checksum mismatch".into());
+    }
+    Ok((version, payload))
+}
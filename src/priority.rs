@@ -0,0 +1,40 @@
+//! Lowering the priority of the current process for background scans.
+
+use std::io;
+
+/// Puts the calling thread into the idle I/O scheduling class (Linux only)
+/// and gives it the lowest CPU priority.
+///
+/// On Linux both priorities are per thread and inherited by threads spawned
+/// afterwards, so this should be called before any scan starts its pool.
+#[cfg(unix)]
+pub fn lower_priority() -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+        const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+        let ioprio = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        // Safety: ioprio_set only takes integers, and 0 targets the calling thread
+        let ret = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) };
+        if ret == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    // Safety: setpriority only takes integers, and 0 targets the calling thread
+    let ret = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, 19) };
+    if ret == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn lower_priority() -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "lowering priority is not supported on this platform",
+    ))
+}
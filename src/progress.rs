@@ -0,0 +1,134 @@
+//! Live counters of a running scan.
+//!
+//! A [`Progress`] is shared with the scan through [`ScanOptions`](crate::ScanOptions)
+//! and can be read at any time with [`Progress::snapshot`], or periodically
+//! from a background thread with [`Progress::report_every`].
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+#[derive(Default)]
+pub struct Progress {
+    files: AtomicU64,
+    dirs: AtomicU64,
+    bytes: AtomicU64,
+    errors: AtomicU64,
+    error_kinds: Mutex<HashMap<io::ErrorKind, u64>>,
+    current_dir: Mutex<PathBuf>,
+}
+
+/// Counters of a [`Progress`] at some point in time.
+#[derive(Clone, Debug, Default)]
+pub struct ProgressSnapshot {
+    pub files: u64,
+    pub dirs: u64,
+    /// Bytes accounted so far, including sizes taken from a folder cache.
+    pub bytes: u64,
+    /// Entries which could not be read.
+    pub errors: u64,
+    /// Number of errors of each kind.
+    pub error_kinds: HashMap<io::ErrorKind, u64>,
+    /// Directory entered most recently.
+    pub current_dir: PathBuf,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        ProgressSnapshot {
+            files: self.files.load(Ordering::Relaxed),
+            dirs: self.dirs.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            error_kinds: self.error_kinds.lock().unwrap().clone(),
+            current_dir: self.current_dir.lock().unwrap().clone(),
+        }
+    }
+
+    /// Sets the counters back to zero, before a scan starting over.
+    pub fn reset(&self) {
+        self.files.store(0, Ordering::Relaxed);
+        self.dirs.store(0, Ordering::Relaxed);
+        self.bytes.store(0, Ordering::Relaxed);
+        self.errors.store(0, Ordering::Relaxed);
+        self.error_kinds.lock().unwrap().clear();
+    }
+
+    /// Calls `callback` with a snapshot every `interval` from a background
+    /// thread, and a last time when the returned [`Reporter`] is finished or dropped.
+    pub fn report_every<F>(self: &Arc<Self>, interval: Duration, mut callback: F) -> Reporter
+    where
+        F: FnMut(&ProgressSnapshot) + Send + 'static,
+    {
+        let (stop, stopped) = mpsc::channel();
+        let progress = Arc::clone(self);
+        let handle = thread::spawn(move || loop {
+            let result = stopped.recv_timeout(interval);
+            callback(&progress.snapshot());
+            if result != Err(RecvTimeoutError::Timeout) {
+                break;
+            }
+        });
+        Reporter {
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    pub(crate) fn add_file(&self, size: u64) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_dir(&self, path: &Path) {
+        self.dirs.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut current_dir) = self.current_dir.try_lock() {
+            current_dir.clear();
+            current_dir.push(path);
+        }
+    }
+
+    pub(crate) fn add_bytes(&self, size: u64) {
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_error(&self, kind: io::ErrorKind) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        *self.error_kinds.lock().unwrap().entry(kind).or_default() += 1;
+    }
+}
+
+/// Background thread started by [`Progress::report_every`].
+pub struct Reporter {
+    stop: Sender<()>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Reporter {
+    /// Stops reporting after a final call of the callback.
+    pub fn finish(mut self) {
+        self.stop_and_join();
+    }
+
+    fn stop_and_join(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            let _ = self.stop.send(());
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Reporter {
+    fn drop(&mut self) {
+        self.stop_and_join();
+    }
+}
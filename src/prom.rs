@@ -0,0 +1,284 @@
+//! Metrics in the Prometheus text format or in OpenMetrics.
+//!
+//! [`Metrics`] collects families of gauge samples and renders them in either
+//! format. Samples added to a family already there join it, so that every
+//! family is written once, as both formats require, whatever the order in
+//! which samples come. [`respond`] serves them over HTTP, and
+//! [`write_textfile`] writes them for the textfile collector of node-exporter.
+
+use crate::escape::escape_name;
+use crate::persist::write_atomic;
+use std::fmt::{self, Display, Write as _};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Time a client gets to send its request, and to read each part of the
+/// response.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests are read up to this many bytes.
+const MAX_REQUEST_LEN: u64 = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Prometheus text format 0.0.4, also read by the textfile collector of
+    /// node-exporter.
+    Prometheus,
+    /// OpenMetrics 1.0, with units and a final `# EOF`.
+    OpenMetrics,
+}
+
+impl Format {
+    /// Value of the `Content-Type` header for metrics in this format.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+            Format::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    families: Vec<Family>,
+}
+
+pub struct Family {
+    name: String,
+    unit: Option<String>,
+    help: String,
+    samples: Vec<Sample>,
+}
+
+struct Sample {
+    labels: Vec<(String, String)>,
+    value: String,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Family `name`, which is added if it is not there yet. The name is
+    /// sanitized with [`metric_name`]. A `unit` must also end the name.
+    pub fn family(&mut self, name: &str, unit: Option<&str>, help: &str) -> &mut Family {
+        let name = metric_name(name);
+        let i = match self.families.iter().position(|family| family.name == name) {
+            Some(i) => i,
+            None => {
+                self.families.push(Family {
+                    name,
+                    unit: unit.map(str::to_owned),
+                    help: help.to_owned(),
+                    samples: Vec::new(),
+                });
+                self.families.len() - 1
+            }
+        };
+        &mut self.families[i]
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.families.is_empty()
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        let mut out = String::new();
+        self.write(&mut out, format)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    fn write(&self, out: &mut String, format: Format) -> fmt::Result {
+        for family in &self.families {
+            family.write(out, format)?;
+        }
+        if format == Format::OpenMetrics {
+            writeln!(out, "# EOF")?;
+        }
+        Ok(())
+    }
+}
+
+impl Family {
+    /// Adds a sample. Label names must be valid, see [`is_label_name`], and
+    /// values are escaped when written.
+    pub fn sample(&mut self, labels: &[(&str, &str)], value: impl Display) -> &mut Self {
+        self.samples.push(Sample {
+            labels: labels
+                .iter()
+                .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+                .collect(),
+            value: value.to_string(),
+        });
+        self
+    }
+
+    fn write(&self, out: &mut String, format: Format) -> fmt::Result {
+        let help = escape(&self.help, format == Format::OpenMetrics);
+        writeln!(out, "# HELP {} {}", self.name, help)?;
+        writeln!(out, "# TYPE {} gauge", self.name)?;
+        if let (Format::OpenMetrics, Some(unit)) = (format, &self.unit) {
+            writeln!(out, "# UNIT {} {}", self.name, unit)?;
+        }
+        for sample in &self.samples {
+            write!(out, "{}", self.name)?;
+            for (i, (name, value)) in sample.labels.iter().enumerate() {
+                let separator = if i == 0 { '{' } else { ',' };
+                write!(out, "{}{}=\"{}\"", separator, name, escape(value, true))?;
+            }
+            if !sample.labels.is_empty() {
+                out.push('}');
+            }
+            writeln!(out, " {}", sample.value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Answers one HTTP request for `/metrics` with the metrics from `render`, or
+/// 503 while there are none yet. OpenMetrics is used when the client accepts it.
+///
+/// Slow or oversized requests are cut short, so that clients cannot hold on to
+/// a connection.
+pub fn respond<F: Fn(Format) -> String>(stream: &TcpStream, render: Option<F>) -> io::Result<()> {
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_LEN));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Headers are read up to the end, so that closing does not reset the connection
+    let mut format = Format::Prometheus;
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        if Instant::now() > deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("accept") && value.contains("application/openmetrics-text")
+            {
+                format = Format::OpenMetrics;
+            }
+        }
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let path = target.split('?').next().unwrap_or("");
+    let (status, content_type, body) = match (method, path, render) {
+        ("GET" | "HEAD", "/metrics", Some(render)) => {
+            ("200 OK", format.content_type(), render(format))
+        }
+        ("GET" | "HEAD", "/metrics", None) => (
+            "503 Service Unavailable",
+            "text/plain; charset=utf-8",
+            "First scan in progress\n".to_owned(),
+        ),
+        ("GET" | "HEAD", _, _) => (
+            "404 Not Found",
+            "text/plain; charset=utf-8",
+            "Not found\n".to_owned(),
+        ),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain; charset=utf-8",
+            "Method not allowed\n".to_owned(),
+        ),
+    };
+    let mut writer = BufWriter::new(stream);
+    write!(
+        writer,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    if method != "HEAD" {
+        writer.write_all(body.as_bytes())?;
+    }
+    writer.flush()
+}
+
+/// Replaces the textfile at `path` with `output`, the metrics of a scan, or
+/// keeps it as it was without them, when the scan failed. The failure is told
+/// by `home_sizes_prom_scan_failed` in a textfile next to it, which is always
+/// written, see [`status_path`].
+pub fn write_textfile(path: &Path, output: Option<&str>, format: Format) -> io::Result<()> {
+    if let Some(output) = output {
+        write_atomic(path, output.as_bytes())?;
+    }
+
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    let mut status = Metrics::new();
+    status
+        .family(
+            "home_sizes_prom_scan_failed",
+            None,
+            "Whether the last scan for a textfile failed, leaving the textfile as it was",
+        )
+        .sample(
+            &[("textfile", &escape_name(file_name))],
+            u8::from(output.is_none()),
+        );
+    write_atomic(&status_path(path), status.render(format).as_bytes())
+}
+
+/// Textfile holding the scan status of the textfile at `path`: `x.prom` gives
+/// `x.status.prom`.
+pub fn status_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default();
+    let mut name = stem.to_os_string();
+    name.push(".status.prom");
+    path.with_file_name(name)
+}
+
+/// Turns `raw` into a valid metric name, matching `[a-zA-Z_:][a-zA-Z0-9_:]*`,
+/// by replacing any other character with `_`, and adding a `_` in front of
+/// a leading digit.
+pub fn metric_name(raw: &str) -> String {
+    let mut name = String::with_capacity(raw.len() + 1);
+    if raw.is_empty() || raw.starts_with(|c: char| c.is_ascii_digit()) {
+        name.push('_');
+    }
+    name.extend(raw.chars().map(|c| {
+        if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+            c
+        } else {
+            '_'
+        }
+    }));
+    name
+}
+
+/// Whether `name` can be the name of a label: `[a-zA-Z_][a-zA-Z0-9_]*`, not
+/// starting with `__`, which is reserved.
+pub fn is_label_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && !name.starts_with("__")
+}
+
+/// Escapes backslashes and line feeds, and double quotes with `quotes`, as in
+/// label values and OpenMetrics help texts.
+fn escape(value: &str, quotes: bool) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '"' if quotes => escaped.push_str("\\\""),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,416 @@
+//! Size limits of directories, to compare with their sizes.
+//!
+//! Limits come either from a TOML file listing directories with their limit,
+//! or on Linux from disk quotas: the block quota of the user owning the
+//! directory, or of the project the directory belongs to, as set with
+//! `xfs_quota` or `chattr -p`. Soft limits are used when set, hard limits
+//! otherwise.
+//!
+//! A limits file looks like:
+//!
+//! ```toml
+//! [limits]
+//! "/home/alice" = "50G"
+//! "/home/bob" = 10737418240
+//! ```
+//!
+//! Sizes are in bytes, or with a `K`, `M`, `G`, `T` or `P` suffix, optionally
+//! followed by `iB` or `B`, all in powers of 1024.
+//!
+//! Reading the limit of one directory may fail, e.g. when it was removed or
+//! cannot be opened, while others still have theirs. Quotas being off or
+//! unsupported fails for all directories of the filesystem instead, which
+//! [`LimitError::is_unavailable`] tells.
+
+use crate::DiskItem;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Display};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+/// Where limits come from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QuotaSource {
+    /// Quota of the user owning each directory.
+    User,
+    /// Quota of the project of each directory.
+    Project,
+    /// Limits listed in a TOML file.
+    File(PathBuf),
+}
+
+impl FromStr for QuotaSource {
+    type Err = String;
+
+    /// `user`, `project`, or else the path of a limits file.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "user" => Ok(QuotaSource::User),
+            "project" => Ok(QuotaSource::Project),
+            "" => Err("empty quota source".to_owned()),
+            path => Ok(QuotaSource::File(PathBuf::from(path))),
+        }
+    }
+}
+
+/// Limits of directories. Disk quotas are read once for each user or
+/// project, so limits changed since show up in a new `Limits` only.
+pub struct Limits {
+    source: QuotaSource,
+    /// Limits of a file, by canonical path when the directory exists.
+    listed: HashMap<PathBuf, u64>,
+    /// Disk quotas read so far, by device and user or project id.
+    quotas: Mutex<HashMap<(u64, u32), Quota>>,
+}
+
+/// Limit from a disk quota, or the error reading it.
+type Quota = Result<Option<u64>, LimitError>;
+
+/// Error reading the limit of a directory.
+#[derive(Clone, Debug)]
+pub struct LimitError {
+    message: String,
+    unavailable: bool,
+}
+
+impl LimitError {
+    /// An error of the whole filesystem, rather than of one directory.
+    fn unavailable(error: io::Error) -> Self {
+        LimitError {
+            message: error.to_string(),
+            unavailable: true,
+        }
+    }
+
+    /// Whether limits cannot be read on the whole filesystem, such as when
+    /// quotas are off, rather than for this directory only.
+    pub fn is_unavailable(&self) -> bool {
+        self.unavailable
+    }
+}
+
+impl From<io::Error> for LimitError {
+    fn from(error: io::Error) -> Self {
+        LimitError {
+            message: error.to_string(),
+            unavailable: false,
+        }
+    }
+}
+
+impl Display for LimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl Error for LimitError {}
+
+/// Limits of the children of a directory.
+pub struct ChildLimits<'a> {
+    /// Children having a limit, with it.
+    pub limits: Vec<(&'a DiskItem, u64)>,
+    /// Children whose limit could not be read, with the error.
+    pub skipped: Vec<(&'a DiskItem, LimitError)>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LimitsFile {
+    limits: HashMap<PathBuf, Size>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Size {
+    Bytes(u64),
+    Text(String),
+}
+
+impl Limits {
+    /// Limits from `source`, reading the file of a [`QuotaSource::File`].
+    pub fn new(source: QuotaSource) -> Result<Self, Box<dyn Error>> {
+        let mut listed = HashMap::new();
+        if let QuotaSource::File(path) = &source {
+            let file: LimitsFile = toml::from_str(&fs::read_to_string(path)?)
+                .map_err(|e| format!("{}: {}", path.display(), e))?;
+            for (dir, size) in file.limits {
+                let limit = match size {
+                    Size::Bytes(bytes) => bytes,
+                    Size::Text(text) => parse_size(&text)
+                        .ok_or_else(|| format!("{}: invalid size {:?}", path.display(), text))?,
+                };
+                let dir = fs::canonicalize(&dir).unwrap_or(dir);
+                listed.insert(dir, limit);
+            }
+        }
+        Ok(Limits {
+            source,
+            listed,
+            quotas: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Limit of the directory at `dir`, if it has one.
+    pub fn limit(&self, dir: &Path) -> Result<Option<u64>, LimitError> {
+        match &self.source {
+            QuotaSource::File(_) => {
+                if let Some(&limit) = self.listed.get(dir) {
+                    return Ok(Some(limit));
+                }
+                Ok(fs::canonicalize(dir)
+                    .ok()
+                    .and_then(|dir| self.listed.get(&dir).copied()))
+            }
+            QuotaSource::User => self.quota(dir, system::Kind::User),
+            QuotaSource::Project => self.quota(dir, system::Kind::Project),
+        }
+    }
+
+    /// Limits of the children of `item`, found at `dir`, skipping those whose
+    /// limit could not be read. Fails only when limits cannot be read on the
+    /// whole filesystem.
+    pub fn children<'a>(
+        &self,
+        dir: &Path,
+        item: &'a DiskItem,
+    ) -> Result<ChildLimits<'a>, LimitError> {
+        let mut children = ChildLimits {
+            limits: Vec::new(),
+            skipped: Vec::new(),
+        };
+        for child in item.children.iter().flatten() {
+            match self.limit(&dir.join(&child.name)) {
+                Ok(Some(limit)) => children.limits.push((child, limit)),
+                Ok(None) => {}
+                Err(e) if e.is_unavailable() => return Err(e),
+                Err(e) => children.skipped.push((child, e)),
+            }
+        }
+        Ok(children)
+    }
+
+    fn quota(&self, dir: &Path, kind: system::Kind) -> Result<Option<u64>, LimitError> {
+        let owner = match system::owner(dir, kind)? {
+            Some(owner) => owner,
+            None => return Ok(None),
+        };
+        let mut quotas = self.quotas.lock().unwrap();
+        quotas
+            .entry(owner)
+            .or_insert_with(|| system::quota(owner, kind))
+            .clone()
+    }
+}
+
+/// Parses a size in bytes, with an optional binary unit suffix.
+pub fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let text = text.strip_suffix('B').unwrap_or(text);
+    let text = text.strip_suffix('i').unwrap_or(text);
+    let (number, exponent) = match text.char_indices().last()? {
+        (i, unit) if unit.is_ascii_alphabetic() => {
+            let exponent = "KMGTP".find(unit.to_ascii_uppercase())? + 1;
+            (&text[..i], exponent as i32)
+        }
+        _ => (text, 0),
+    };
+    let number = number.trim().parse::<f64>().ok()?;
+    let bytes = number * 1024f64.powi(exponent);
+    (bytes.is_finite() && bytes >= 0.0 && bytes < u64::MAX as f64).then_some(bytes.round() as u64)
+}
+
+#[cfg(target_os = "linux")]
+mod system {
+    use super::LimitError;
+    use std::ffi::CString;
+    use std::fs::{self, File};
+    use std::io;
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    #[derive(Clone, Copy)]
+    pub(super) enum Kind {
+        User,
+        Project,
+    }
+
+    const USRQUOTA: libc::c_int = 0;
+    const PRJQUOTA: libc::c_int = 2;
+    /// Quota limits are counted in blocks of this size.
+    const QIF_DQBLKSIZE: u64 = 1024;
+    const FS_IOC_FSGETXATTR: libc::c_ulong = 0x801c_581f;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct FsXAttr {
+        xflags: u32,
+        extsize: u32,
+        nextents: u32,
+        projid: u32,
+        cowextsize: u32,
+        pad: [u8; 8],
+    }
+
+    /// Device and id of the user or project whose quota applies to `dir`, if
+    /// it is a directory.
+    pub(super) fn owner(dir: &Path, kind: Kind) -> Result<Option<(u64, u32)>, LimitError> {
+        let metadata = fs::metadata(dir)?;
+        if !metadata.is_dir() {
+            return Ok(None);
+        }
+        let id = match kind {
+            Kind::User => metadata.uid(),
+            Kind::Project => match project_id(dir)? {
+                // Project 0 is the default one of files outside of projects
+                0 => return Ok(None),
+                id => id,
+            },
+        };
+        Ok(Some((metadata.dev(), id)))
+    }
+
+    /// Block limit of the user or project `id` on device `dev`.
+    pub(super) fn quota((dev, id): (u64, u32), kind: Kind) -> Result<Option<u64>, LimitError> {
+        let quota_type = match kind {
+            Kind::User => USRQUOTA,
+            Kind::Project => PRJQUOTA,
+        };
+        let device = device(dev).map_err(LimitError::unavailable)?;
+        let device = CString::new(device).map_err(|e| LimitError::unavailable(e.into()))?;
+        let mut quota = std::mem::MaybeUninit::<libc::dqblk>::uninit();
+        // Safety: `device` is a valid C string and `quota` is a dqblk
+        let result = unsafe {
+            libc::quotactl(
+                libc::QCMD(libc::Q_GETQUOTA, quota_type),
+                device.as_ptr(),
+                id as libc::c_int,
+                quota.as_mut_ptr() as *mut libc::c_char,
+            )
+        };
+        // Ids without a quota get zero limits, so errors are real ones, most
+        // of which hold for the whole filesystem, such as ESRCH when quotas
+        // are off on it
+        if result == -1 {
+            let error = io::Error::last_os_error();
+            return Err(match error.raw_os_error() {
+                Some(libc::EPERM | libc::EACCES) => error.into(),
+                _ => LimitError::unavailable(error),
+            });
+        }
+        // Safety: the quota was filled by a successful call
+        let quota = unsafe { quota.assume_init() };
+        if quota.dqb_valid & libc::QIF_BLIMITS == 0 {
+            return Ok(None);
+        }
+        let blocks = match quota.dqb_bsoftlimit {
+            0 => quota.dqb_bhardlimit,
+            soft => soft,
+        };
+        Ok((blocks > 0).then_some(blocks * QIF_DQBLKSIZE))
+    }
+
+    fn project_id(dir: &Path) -> Result<u32, LimitError> {
+        let file = File::open(dir)?;
+        let mut attr = FsXAttr::default();
+        // Safety: `attr` has the layout of struct fsxattr
+        let result = unsafe {
+            libc::ioctl(
+                file.as_raw_fd(),
+                FS_IOC_FSGETXATTR as _,
+                &mut attr as *mut FsXAttr,
+            )
+        };
+        if result == -1 {
+            let error = io::Error::last_os_error();
+            // Filesystems without projects do not know the ioctl
+            return Err(match error.raw_os_error() {
+                Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL) => {
+                    LimitError::unavailable(error)
+                }
+                _ => error.into(),
+            });
+        }
+        Ok(attr.projid)
+    }
+
+    /// Block device of the filesystem of device id `dev`, from the mounts of
+    /// the process.
+    fn device(dev: u64) -> io::Result<Vec<u8>> {
+        let wanted = format!("{}:{}", libc::major(dev), libc::minor(dev));
+        let mountinfo = fs::read("/proc/self/mountinfo")?;
+        for line in mountinfo.split(|&b| b == b'\n') {
+            let mut fields = line.split(|&b| b == b' ');
+            if fields.nth(2) != Some(wanted.as_bytes()) {
+                continue;
+            }
+            // Optional fields end with a lone dash, followed by the
+            // filesystem type and the source
+            let mut after_dash = fields.skip_while(|&field| field != b"-").skip(2);
+            if let Some(source) = after_dash.next() {
+                return Ok(unescape_mount_field(source));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no mount found for device {}", wanted),
+        ))
+    }
+
+    /// Undoes the octal escapes of spaces and other characters in mountinfo.
+    fn unescape_mount_field(field: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(field.len());
+        let mut i = 0;
+        while i < field.len() {
+            let octal = field.get(i + 1..i + 4).and_then(|digits| {
+                let digits = std::str::from_utf8(digits).ok()?;
+                u8::from_str_radix(digits, 8).ok()
+            });
+            match (field[i], octal) {
+                (b'\\', Some(byte)) => {
+                    bytes.push(byte);
+                    i += 4;
+                }
+                (byte, _) => {
+                    bytes.push(byte);
+                    i += 1;
+                }
+            }
+        }
+        bytes
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod system {
+    use super::LimitError;
+    use std::io;
+    use std::path::Path;
+
+    #[derive(Clone, Copy)]
+    pub(super) enum Kind {
+        User,
+        Project,
+    }
+
+    pub(super) fn owner(_dir: &Path, _kind: Kind) -> Result<Option<(u64, u32)>, LimitError> {
+        Err(unsupported())
+    }
+
+    pub(super) fn quota(_owner: (u64, u32), _kind: Kind) -> Result<Option<u64>, LimitError> {
+        Err(unsupported())
+    }
+
+    fn unsupported() -> LimitError {
+        LimitError::unavailable(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "disk quotas are only read on Linux",
+        ))
+    }
+}
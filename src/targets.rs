@@ -0,0 +1,100 @@
+//! Directories scanned by `home-sizes-prom`, as listed in a TOML file.
+//!
+//! ```toml
+//! [[target]]
+//! path = "/home"
+//! cache = "/var/lib/home-sizes/home.msgpack"
+//! labels = { volume = "home" }
+//!
+//! [[target]]
+//! path = "/data"
+//! depth = 3
+//! drift_threshold = 1.0
+//! ```
+//!
+//! Options left out take the defaults of the command line.
+
+use crate::prom::is_label_name;
+use clap::ArgEnum;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Label names set by `home-sizes-prom` itself, which targets cannot use.
+pub const RESERVED_LABELS: &[&str] = &["name", "path", "kind", "state"];
+
+pub type Labels = BTreeMap<String, String>;
+
+/// A directory to scan, with the options of the command line that may differ
+/// from one target to the other.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub path: PathBuf,
+    #[serde(default = "default_depth")]
+    pub depth: usize,
+    #[serde(default = "default_valid_days")]
+    pub valid_days: u64,
+    #[serde(default = "default_parent_valid_days")]
+    pub parent_valid_days: u64,
+    #[serde(default = "default_expire_hours")]
+    pub expire_hours: u64,
+    pub cache: Option<PathBuf>,
+    pub index: Option<PathBuf>,
+    pub drift_threshold: Option<f64>,
+    #[serde(default)]
+    pub drift_invalidation: DriftInvalidation,
+    /// Labels added to every metric of the target.
+    #[serde(default)]
+    pub labels: Labels,
+}
+
+/// What to rescan when the unaccounted bytes of a volume drift.
+#[derive(ArgEnum, Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftInvalidation {
+    #[default]
+    Full,
+    Subtree,
+}
+
+/// Contents of a targets file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TargetsFile {
+    #[serde(rename = "target")]
+    targets: Vec<Target>,
+}
+
+/// Reads the targets listed in the TOML file at `path`, checking their labels.
+pub fn load(path: &Path) -> Result<Vec<Target>, Box<dyn Error>> {
+    let file: TargetsFile = toml::from_str(&fs::read_to_string(path)?)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    for (name, _) in file.targets.iter().flat_map(|target| &target.labels) {
+        if !is_label_name(name) {
+            return Err(format!("{}: invalid label name {:?}", path.display(), name).into());
+        }
+        if RESERVED_LABELS.contains(&name.as_str()) {
+            return Err(format!("{}: label name {:?} is reserved", path.display(), name).into());
+        }
+    }
+    Ok(file.targets)
+}
+
+fn default_depth() -> usize {
+    2
+}
+
+fn default_valid_days() -> u64 {
+    7
+}
+
+fn default_parent_valid_days() -> u64 {
+    365
+}
+
+fn default_expire_hours() -> u64 {
+    24
+}
@@ -1,11 +1,30 @@
-use crate::{DiskItem, FileInfo};
+use crate::arena::ArenaTree;
+use crate::backend::{DirEntry, EntryKind, FsBackend, MemoryFs, RealFs};
+use crate::cache::{Cache, CacheKey, CachedSize, Generation};
+use crate::drift::Drift;
+use crate::escape::unescape_name;
+use crate::index::DirIndex;
+use crate::prom::{
+    is_label_name, metric_name, respond, status_path, write_textfile, Format, Metrics,
+};
+use crate::quota::{parse_size, Limits, QuotaSource};
+use crate::targets::{self, DriftInvalidation};
+use crate::volume::VolumeUsage;
+use crate::{
+    AnalyzeConfig, CancelToken, DiskItem, FileInfo, IoLimit, Progress, RateLimit, ScanOptions,
+    SortBy, SortKey, TreeEntry,
+};
 // warn: don't remove `as &str` after macro invocation.
 // It breaks type checker in Intellij Rust IDE
 use const_format::concatcp;
+use std::ffi::{OsStr, OsString};
 use std::fs::File;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
 use std::panic;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 
 // be aware that rust runs tests in parallel, so tests should use different dirs
 
@@ -113,6 +132,1330 @@ fn test_file_size() {
     }
 }
 
+#[test]
+#[cfg(unix)]
+fn test_hardlink_counted_once() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_hardlink_counted_once/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    create_file(concatcp!(DIR, "foo/file.bin") as &str, 8192);
+    create_file(concatcp!(DIR, "foo/other.bin") as &str, 100);
+    std::fs::hard_link(
+        concatcp!(DIR, "foo/file.bin") as &str,
+        concatcp!(DIR, "foo/link.bin") as &str,
+    )
+    .unwrap();
+
+    // When
+    let test_path = Path::new(DIR);
+    let result = FileInfo::from_path(test_path, false);
+
+    // Then
+    if let Result::Ok(FileInfo::Directory { volume_id, .. }) = result {
+        let result = DiskItem::from_analyze(test_path, false, volume_id, usize::MAX);
+        let result = result.expect("Must collect data");
+        assert_eq!(result.disk_size, 8192 + 100);
+        assert_eq!(result.file_count, 2);
+        let foo = &result.children.unwrap()[0];
+        assert_eq!(foo.disk_size, 8192 + 100);
+        assert_eq!(foo.file_count, 2);
+        assert_eq!(foo.children.as_ref().unwrap().len(), 3);
+    } else {
+        panic!("Can not get file info");
+    }
+}
+
+#[test]
+fn test_sort_by_name() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_sort_by_name/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    create_file(concatcp!(DIR, "file10") as &str, 10);
+    create_file(concatcp!(DIR, "file2") as &str, 20);
+    create_file(concatcp!(DIR, "File1") as &str, 30);
+    create_file(concatcp!(DIR, "file02") as &str, 20);
+
+    // When
+    let test_path = Path::new(DIR);
+    let result = FileInfo::from_path(test_path, false);
+
+    // Then
+    if let Result::Ok(FileInfo::Directory { volume_id, .. }) = result {
+        let mut result = DiskItem::from_analyze(test_path, false, volume_id, usize::MAX)
+            .expect("Must collect data");
+        let names = |item: &DiskItem| {
+            item.children
+                .iter()
+                .flatten()
+                .map(|child| child.name.clone())
+                .collect::<Vec<_>>()
+        };
+        // Equal sizes are ordered by name
+        assert_eq!(names(&result), ["File1", "file02", "file2", "file10"]);
+
+        result.sort(SortBy {
+            key: SortKey::Name,
+            descending: false,
+        });
+        assert_eq!(names(&result), ["File1", "file02", "file2", "file10"]);
+
+        result.sort(SortBy {
+            key: SortKey::Name,
+            descending: true,
+        });
+        assert_eq!(names(&result), ["file10", "file2", "file02", "File1"]);
+    } else {
+        panic!("Can not get file info");
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_hardlinks_across_targets() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_hardlinks_across_targets/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    create_file(concatcp!(DIR, "a/file.bin") as &str, 8192);
+    create_file(concatcp!(DIR, "b/other.bin") as &str, 100);
+    std::fs::hard_link(
+        concatcp!(DIR, "a/file.bin") as &str,
+        concatcp!(DIR, "b/link.bin") as &str,
+    )
+    .unwrap();
+
+    // When scanning the parent, links in sibling dirs are counted once
+    let test_path = Path::new(DIR);
+    if let Ok(FileInfo::Directory { volume_id, .. }) = FileInfo::from_path(test_path, false) {
+        let result = DiskItem::from_analyze(test_path, false, volume_id, usize::MAX);
+        let result = result.expect("Must collect data");
+        assert_eq!(result.disk_size, 8192 + 100);
+    } else {
+        panic!("Can not get file info");
+    }
+
+    // When scanning both dirs and a nested one as separate targets
+    let targets = [
+        PathBuf::from(concatcp!(DIR, "a") as &str),
+        PathBuf::from(concatcp!(DIR, "b") as &str),
+        PathBuf::from(DIR),
+    ];
+    let result =
+        DiskItem::from_analyze_targets(&targets, false, usize::MAX, &ScanOptions::default());
+
+    // Then each tree is complete, but the totals count everything once
+    assert_eq!(result.items[0].as_ref().unwrap().disk_size, 8192);
+    assert_eq!(result.items[1].as_ref().unwrap().disk_size, 8192 + 100);
+    assert_eq!(result.nested, [0, 1]);
+    assert_eq!(result.disk_size, 8192 + 100);
+    assert_eq!(result.file_count, 2);
+
+    // Then links shared by separate targets are counted once as well
+    let result =
+        DiskItem::from_analyze_targets(&targets[..2], false, usize::MAX, &ScanOptions::default());
+    assert!(result.nested.is_empty());
+    assert_eq!(result.disk_size, 8192 + 100);
+}
+
+#[test]
+fn test_progress_counts() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_progress_counts/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    create_file(concatcp!(DIR, "foo/file.bin") as &str, 1000);
+    create_file(concatcp!(DIR, "foo/bar/file.bin") as &str, 200);
+    create_file(concatcp!(DIR, "file.bin") as &str, 30);
+
+    // When
+    let test_path = Path::new(DIR);
+    let progress = Arc::new(Progress::new());
+    let options = ScanOptions {
+        progress: Some(Arc::clone(&progress)),
+        ..ScanOptions::default()
+    };
+    if let Ok(FileInfo::Directory { volume_id, .. }) = FileInfo::from_path(test_path, false) {
+        DiskItem::from_analyze_with(test_path, false, volume_id, 1, &options)
+            .expect("Must collect data");
+    } else {
+        panic!("Can not get file info");
+    }
+
+    // Then
+    let snapshot = progress.snapshot();
+    assert_eq!(snapshot.files, 3);
+    assert_eq!(snapshot.dirs, 3);
+    assert_eq!(snapshot.bytes, 1230);
+    assert_eq!(snapshot.errors, 0);
+}
+
+#[test]
+fn test_cancelled_scan_is_incomplete() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_cancelled_scan_is_incomplete/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    create_file(concatcp!(DIR, "foo/file.bin") as &str, 1000);
+    create_file(concatcp!(DIR, "file.bin") as &str, 30);
+    let cancel = CancelToken::new();
+    cancel.cancel();
+
+    // When
+    let test_path = Path::new(DIR);
+    let options = ScanOptions {
+        cancel: Some(cancel),
+        ..ScanOptions::default()
+    };
+    if let Ok(FileInfo::Directory { volume_id, .. }) = FileInfo::from_path(test_path, false) {
+        let result = DiskItem::from_analyze_with(test_path, false, volume_id, 1, &options)
+            .expect("Must return partial data");
+
+        // Then
+        assert!(result.incomplete);
+        assert_eq!(result.disk_size, 0);
+        assert!(result.children.unwrap().is_empty());
+    } else {
+        panic!("Can not get file info");
+    }
+
+    // When the deadline is not reached
+    let options = ScanOptions {
+        deadline: Some(Instant::now() + Duration::from_secs(3600)),
+        ..ScanOptions::default()
+    };
+    let result = DiskItem::from_analyze_targets(&[PathBuf::from(DIR)], false, 1, &options);
+
+    // Then
+    let result = result.items[0].as_ref().expect("Must collect data");
+    assert!(!result.incomplete);
+    assert_eq!(result.disk_size, 1030);
+}
+
+#[test]
+#[cfg(unix)]
+fn test_out_of_fds_is_incomplete() {
+    // Given a folder that cannot be read
+    let mut fs = MemoryFs::new();
+    fs.add_file("/home/alice/a", 1000);
+    fs.add_file("/home/bob/b", 2000);
+    fs.set_open_error("/home/bob", libc::EACCES);
+    let progress = Arc::new(Progress::new());
+    let options = ScanOptions {
+        progress: Some(Arc::clone(&progress)),
+        ..ScanOptions::default()
+    };
+    let home = Path::new("/home");
+
+    // When
+    let result = DiskItem::from_analyze_in(&fs, home, false, 1, usize::MAX, &options)
+        .expect("Must collect data");
+
+    // Then it is left out of a complete scan
+    assert!(!result.incomplete);
+    assert_eq!(result.disk_size, 1000);
+    assert_eq!(progress.snapshot().errors, 1);
+
+    // When the scan runs out of file descriptors instead
+    fs.set_open_error("/home/bob", libc::EMFILE);
+    let result = DiskItem::from_analyze_in(&fs, home, false, 1, usize::MAX, &options)
+        .expect("Must collect data");
+
+    // Then the scan is incomplete
+    assert!(result.incomplete);
+    assert_eq!(result.disk_size, 1000);
+}
+
+#[test]
+fn test_dedicated_pool_and_io_limit() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_dedicated_pool_and_io_limit/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    for i in 0..10 {
+        create_file(&format!("{}dir{}/file.bin", DIR, i), 100);
+    }
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(2)
+        .build()
+        .unwrap();
+    let options = ScanOptions {
+        pool: Some(Arc::new(pool)),
+        io_limit: Some(Arc::new(IoLimit::new(1))),
+        ..ScanOptions::default()
+    };
+
+    // When
+    let result = DiskItem::from_analyze_targets(&[PathBuf::from(DIR)], false, 2, &options);
+
+    // Then
+    let result = result.items[0].as_ref().expect("Must collect data");
+    assert_eq!(result.disk_size, 1000);
+    assert_eq!(result.children.as_ref().unwrap().len(), 10);
+}
+
+#[test]
+fn test_stat_rate_limit() {
+    // Given a limit of 100 stat calls per second
+    let stat_rate = RateLimit::new(100);
+
+    // When making 11 calls
+    let started = Instant::now();
+    for _ in 0..11 {
+        stat_rate.wait();
+    }
+
+    // Then the first one goes through at once and the others wait their turn
+    assert!(started.elapsed() >= Duration::from_millis(100));
+}
+
+#[test]
+fn test_arena_tree_matches_disk_item() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_arena_tree_matches_disk_item/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    create_file(concatcp!(DIR, "a/one") as &str, 100);
+    create_file(concatcp!(DIR, "a/b/two") as &str, 200);
+    create_file(concatcp!(DIR, "a/b/c/three") as &str, 300);
+    create_file(concatcp!(DIR, "d/four") as &str, 400);
+    create_file(concatcp!(DIR, "five") as &str, 500);
+
+    // When
+    let test_path = Path::new(DIR);
+    let result = FileInfo::from_path(test_path, false);
+
+    // Then
+    if let Result::Ok(FileInfo::Directory { volume_id, .. }) = result {
+        let options = ScanOptions::default();
+        let expected = DiskItem::from_analyze_with(test_path, false, volume_id, 2, &options)
+            .expect("Must collect data");
+        let arena = ArenaTree::from_analyze_with(test_path, false, volume_id, 2, &options)
+            .expect("Must collect data");
+        // root, a, d, five, one, b, four
+        assert_eq!(arena.len(), 7);
+        assert_eq!(arena.root().children().unwrap().count(), 3);
+        assert_eq!(
+            serde_json::to_value(arena.root().to_disk_item()).unwrap(),
+            serde_json::to_value(expected).unwrap()
+        );
+    } else {
+        panic!("Cannot get file info");
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_non_utf8_name_round_trip() {
+    use std::os::unix::ffi::OsStrExt;
+
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_non_utf8_name_round_trip/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    let name = OsStr::from_bytes(b"caf\xe9 \\x41");
+    File::create(Path::new(DIR).join(name)).unwrap();
+
+    // When
+    let test_path = Path::new(DIR);
+    let result = FileInfo::from_path(test_path, false);
+
+    // Then
+    if let Result::Ok(FileInfo::Directory { volume_id, .. }) = result {
+        let result = DiskItem::from_analyze(test_path, false, volume_id, usize::MAX)
+            .expect("Must collect data");
+        let child = &result.children.unwrap()[0];
+        assert_eq!(child.name, name);
+
+        let json = serde_json::to_value(child).unwrap();
+        assert_eq!(json["name"], "caf\\xE9 \\\\x41");
+        let unescaped = unescape_name(json["name"].as_str().unwrap()).unwrap();
+        assert_eq!(unescaped, name);
+        assert!(Path::new(DIR).join(unescaped).exists());
+    } else {
+        panic!("Cannot get file info");
+    }
+}
+
+#[test]
+fn test_tree_paths() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_tree_paths/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    create_file(concatcp!(DIR, "alice/.cache/big") as &str, 300);
+    create_file(concatcp!(DIR, "alice/notes") as &str, 100);
+    create_file(concatcp!(DIR, "bob/file") as &str, 200);
+
+    // When
+    let test_path = Path::new(DIR);
+    let result = FileInfo::from_path(test_path, false);
+
+    // Then
+    if let Result::Ok(FileInfo::Directory { volume_id, .. }) = result {
+        let result = DiskItem::from_analyze(test_path, false, volume_id, usize::MAX)
+            .expect("Must collect data");
+        let root = Path::new("/home");
+
+        let cache = result.get(Path::new("alice/.cache")).unwrap();
+        assert_eq!(cache.disk_size, 300);
+        assert!(result.get(Path::new("alice/missing")).is_none());
+        assert!(result.get(Path::new("../alice")).is_none());
+
+        let ancestors = result.ancestors(Path::new("./alice/.cache/big")).unwrap();
+        let names = ancestors.iter().map(|item| &item.name).collect::<Vec<_>>();
+        assert_eq!(names, ["big", ".cache", "alice", "test_tree_paths"]);
+
+        let paths = |entries: &mut dyn Iterator<Item = TreeEntry>| {
+            entries
+                .map(|entry| entry.path.to_string_lossy().into_owned())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            paths(&mut result.depth_first(root)),
+            [
+                "/home",
+                "/home/alice",
+                "/home/alice/.cache",
+                "/home/alice/.cache/big",
+                "/home/alice/notes",
+                "/home/bob",
+                "/home/bob/file",
+            ]
+        );
+        assert_eq!(
+            paths(&mut result.breadth_first(root)),
+            [
+                "/home",
+                "/home/alice",
+                "/home/bob",
+                "/home/alice/.cache",
+                "/home/alice/notes",
+                "/home/bob/file",
+                "/home/alice/.cache/big",
+            ]
+        );
+
+        let found = result
+            .find(root, |entry| entry.depth == 2 && entry.item.disk_size > 250)
+            .unwrap();
+        assert_eq!(found.path, Path::new("/home/alice/.cache"));
+    } else {
+        panic!("Cannot get file info");
+    }
+}
+
+#[test]
+#[cfg(unix)]
+fn test_special_files_are_empty() {
+    use std::ffi::CString;
+
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_special_files_are_empty/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    create_file(concatcp!(DIR, "sub/file") as &str, 100);
+    let fifo = CString::new(concatcp!(DIR, "sub/fifo")).unwrap();
+    assert_eq!(unsafe { libc::mkfifo(fifo.as_ptr(), 0o600) }, 0);
+
+    // When
+    let test_path = Path::new(DIR);
+    let result = FileInfo::from_path(test_path, false);
+
+    // Then
+    if let Result::Ok(FileInfo::Directory { volume_id, .. }) = result {
+        let result = DiskItem::from_analyze(test_path, false, volume_id, usize::MAX)
+            .expect("Must collect data");
+        assert_eq!(result.disk_size, 100);
+        assert_eq!(result.file_count, 2);
+        let fifo = result.get(Path::new("sub/fifo")).unwrap();
+        assert_eq!(fifo.disk_size, 0);
+        assert_eq!(fifo.file_count, 1);
+    } else {
+        panic!("Cannot get file info");
+    }
+
+    // When the FIFO is opened as a file, as if it replaced an archive
+    let sub = RealFs.open(Path::new(concatcp!(DIR, "sub"))).unwrap();
+    let entry = DirEntry {
+        name: OsString::from("fifo"),
+        kind: EntryKind::File,
+    };
+    let opened = RealFs.open_file(&sub, &entry);
+
+    // Then it fails instead of waiting for a writer
+    assert_eq!(
+        opened.err().unwrap().kind(),
+        std::io::ErrorKind::InvalidInput
+    );
+}
+
+#[test]
+fn test_memory_fs_hardlinks() {
+    // Given
+    let mut fs = MemoryFs::new();
+    fs.add_file("/data/a/file", 8192);
+    fs.add_hard_link("/data/b/link", "/data/a/file");
+    fs.add_file("/data/c/small", 100);
+
+    // When
+    let result = fs.stat(Path::new("/data"), false);
+
+    // Then
+    if let Result::Ok(FileInfo::Directory { volume_id, .. }) = result {
+        let options = ScanOptions::default();
+        let result =
+            DiskItem::from_analyze_in(&fs, Path::new("/data"), false, volume_id, 1, &options)
+                .expect("Must collect data");
+        assert_eq!(result.disk_size, 8192 + 100);
+        assert_eq!(result.allocated_size, 8192 + 4096);
+        assert_eq!(result.file_count, 2);
+        assert_eq!(result.get(Path::new("a")).unwrap().disk_size, 8192);
+        assert_eq!(result.get(Path::new("b")).unwrap().disk_size, 8192);
+        assert_eq!(result.get(Path::new("c")).unwrap().disk_size, 100);
+    } else {
+        panic!("Cannot get file info");
+    }
+}
+
+#[test]
+fn test_memory_fs_cache() {
+    // Given
+    let now = SystemTime::now();
+    let month_ago = now - Duration::from_secs(30 * 24 * 60 * 60);
+    let mut fs = MemoryFs::new();
+    fs.set_time(month_ago);
+    fs.add_file("/home/alice/old/a", 1000);
+    fs.add_file("/home/alice/old/b", 2000);
+    fs.add_file("/home/bob/x", 500);
+    fs.set_modified("/home", now);
+    let config = || AnalyzeConfig {
+        root_dev: 1,
+        cache_valid_duration: Duration::from_secs(7 * 24 * 60 * 60),
+        parent_cold_duration: Duration::from_secs(7 * 24 * 60 * 60),
+        apparent: false,
+        sort: None,
+        options: ScanOptions::default(),
+    };
+    let home = Path::new("/home");
+    let cache = Cache::new(1, home, now + Duration::from_secs(24 * 60 * 60));
+    let scan = |fs: &MemoryFs| {
+        DiskItem::with_cache_in(fs, home, config(), 1, &cache).expect("Must collect data")
+    };
+
+    // When scanning cold folders for the first time
+    let result = scan(&fs);
+
+    // Then their sizes are cached: alice, alice/old and bob
+    assert_eq!(result.get(Path::new("alice")).unwrap().disk_size, 3000);
+    assert_eq!(cache.len(), 3);
+    cache.prune();
+    assert_eq!(cache.len(), 3);
+
+    // When a file grows, which leaves its folders untouched
+    fs.set_size("/home/alice/old/a", 5000);
+    let result = scan(&fs);
+
+    // Then the cached size is used
+    assert_eq!(result.get(Path::new("alice")).unwrap().disk_size, 3000);
+    assert_eq!(cache.used(), 2);
+    assert_eq!(cache.hits(), 2);
+
+    // When a file is added, which makes its folder recent
+    fs.set_time(now);
+    fs.add_file("/home/alice/new", 100);
+    let result = scan(&fs);
+
+    // Then the folder is scanned again, but not its untouched subfolder
+    assert_eq!(
+        result.get(Path::new("alice")).unwrap().disk_size,
+        3000 + 100
+    );
+    assert_eq!(result.get(Path::new("bob")).unwrap().disk_size, 500);
+}
+
+#[test]
+fn test_cancelled_scan_keeps_cache() {
+    // Given a cache of alice, alice/old and bob
+    let now = SystemTime::now();
+    let mut fs = MemoryFs::new();
+    fs.set_time(now - Duration::from_secs(30 * 24 * 60 * 60));
+    fs.add_file("/home/alice/old/a", 1000);
+    fs.add_file("/home/bob/x", 500);
+    fs.set_modified("/home", now);
+    let config = |cancel: Option<CancelToken>| AnalyzeConfig {
+        root_dev: 1,
+        cache_valid_duration: Duration::from_secs(7 * 24 * 60 * 60),
+        parent_cold_duration: Duration::from_secs(7 * 24 * 60 * 60),
+        apparent: false,
+        sort: None,
+        options: ScanOptions {
+            cancel,
+            ..ScanOptions::default()
+        },
+    };
+    let home = Path::new("/home");
+    let cache = Cache::new(1, home, now + Duration::from_secs(24 * 60 * 60));
+    let result = DiskItem::with_cache_in(&fs, home, config(None), 1, &cache).unwrap();
+    cache.prune_after(&result);
+    assert_eq!(cache.len(), 3);
+
+    // When a scan is cancelled before reaching any folder
+    let cancel = CancelToken::new();
+    cancel.cancel();
+    let result = DiskItem::with_cache_in(&fs, home, config(Some(cancel)), 1, &cache).unwrap();
+    cache.prune_after(&result);
+
+    // Then the folders it did not visit are still cached
+    assert!(result.incomplete);
+    assert_eq!(cache.used(), 0);
+    assert_eq!(cache.len(), 3);
+
+    // When a scan completes after bob was removed
+    fs.remove("/home/bob");
+    let result = DiskItem::with_cache_in(&fs, home, config(None), 1, &cache).unwrap();
+    cache.prune_after(&result);
+
+    // Then only the folders it used are kept
+    assert!(!result.incomplete);
+    assert_eq!(cache.len(), 2);
+}
+
+#[test]
+fn test_drift_rescans_subtrees() {
+    // Given cached sizes of carol, alice and bob, and a recent folder
+    let now = SystemTime::now();
+    let mut fs = MemoryFs::new();
+    fs.set_time(now - Duration::from_secs(30 * 24 * 60 * 60));
+    fs.add_file("/home/carol/c", 4000);
+    fs.add_file("/home/alice/old/a", 1000);
+    fs.add_file("/home/bob/x", 500);
+    fs.set_time(now);
+    fs.add_file("/home/dave/new", 200);
+    let progress = Arc::new(Progress::new());
+    let config = || AnalyzeConfig {
+        root_dev: 1,
+        cache_valid_duration: Duration::from_secs(7 * 24 * 60 * 60),
+        parent_cold_duration: Duration::from_secs(7 * 24 * 60 * 60),
+        apparent: false,
+        sort: None,
+        options: ScanOptions {
+            progress: Some(Arc::clone(&progress)),
+            ..ScanOptions::default()
+        },
+    };
+    let home = Path::new("/home");
+    let mut cache = Cache::new(1, home, now + Duration::from_secs(24 * 60 * 60));
+    let drift = |used: u64, invalidation| Drift {
+        usage: VolumeUsage {
+            total: 100_000,
+            used,
+        },
+        threshold: 1.0,
+        invalidation,
+    };
+    // Children scanned again, `None` for the whole target, and unaccounted bytes
+    let rescan = |fs: &MemoryFs, analysed: &mut DiskItem, cache: &mut Cache, used: u64, mode| {
+        let mut rescanned = Vec::new();
+        let scan = |analysed: &mut DiskItem, cache: &Cache, child: Option<&OsStr>| {
+            rescanned.push(child.map(OsStr::to_os_string));
+            match child {
+                Some(name) => analysed.rescan_child_in(fs, home, name, config(), 1, cache),
+                None => {
+                    *analysed = DiskItem::with_cache_in(fs, home, config(), 1, cache)?;
+                    Ok(())
+                }
+            }
+        };
+        let unaccounted = drift(used, mode).rescan(analysed, cache, scan).unwrap();
+        (rescanned, unaccounted)
+    };
+    let mut analysed = DiskItem::with_cache_in(&fs, home, config(), 1, &cache).unwrap();
+    let (rescanned, unaccounted) = rescan(
+        &fs,
+        &mut analysed,
+        &mut cache,
+        5700 + 300,
+        DriftInvalidation::Subtree,
+    );
+    assert!(rescanned.is_empty());
+    assert_eq!((unaccounted, cache.unaccounted), (300, Some(300)));
+    assert_eq!(cache.len(), 4);
+
+    // When a file grows by more than the threshold without touching alice
+    fs.set_size("/home/alice/old/a", 3000);
+    let mut analysed = DiskItem::with_cache_in(&fs, home, config(), 1, &cache).unwrap();
+    assert_eq!(analysed.disk_size, 5700);
+    progress.reset();
+    let (rescanned, unaccounted) = rescan(
+        &fs,
+        &mut analysed,
+        &mut cache,
+        7700 + 300,
+        DriftInvalidation::Subtree,
+    );
+
+    // Then the largest cached children are scanned again, alone, until alice
+    // brings the drift back under the threshold
+    let carol_alice = vec![Some(OsString::from("carol")), Some(OsString::from("alice"))];
+    assert_eq!(rescanned, carol_alice);
+    assert_eq!(unaccounted, 300);
+    assert_eq!(analysed.disk_size, 7700);
+    assert_eq!(analysed.get(Path::new("alice")).unwrap().disk_size, 3000);
+    assert_eq!(analysed.get(Path::new("dave")).unwrap().disk_size, 200);
+    let snapshot = progress.snapshot();
+    assert_eq!((snapshot.files, snapshot.dirs), (2, 3));
+
+    // When the drift comes back in full invalidation mode
+    fs.set_size("/home/bob/x", 3000);
+    let mut analysed = DiskItem::with_cache_in(&fs, home, config(), 1, &cache).unwrap();
+    let (rescanned, unaccounted) = rescan(
+        &fs,
+        &mut analysed,
+        &mut cache,
+        10200 + 300,
+        DriftInvalidation::Full,
+    );
+
+    // Then everything is scanned again once
+    assert_eq!(rescanned, vec![None]);
+    assert_eq!(unaccounted, 300);
+    assert_eq!(analysed.disk_size, 10200);
+}
+
+#[test]
+fn test_cache_merge_and_save() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_cache_merge_and_save/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    let now = SystemTime::now();
+    let hour_ago = now - Duration::from_secs(60 * 60);
+    let home = Path::new("/home");
+    let key = |file_id| CacheKey {
+        volume_id: 1,
+        file_id,
+        generation: Generation::Inode(1),
+    };
+    let size = |last_modified, disk_size| CachedSize {
+        last_modified,
+        disk_size,
+        path: PathBuf::from("folder"),
+        scanned: now,
+    };
+    let mut cache = Cache::new(1, home, now + Duration::from_secs(60 * 60));
+    cache.unaccounted = Some(-4096);
+    cache.store(key(10), size(hour_ago, 1000));
+    cache.store(key(11), size(hour_ago, 2000));
+    let other = Cache::new(1, home, now);
+    other.store(key(11), size(now, 2500));
+    other.store(key(12), size(hour_ago, 3000));
+
+    // When
+    cache.merge(other);
+    let cache_path = Path::new(DIR).join("cache.msgpack");
+    cache.save(&cache_path).unwrap();
+    let loaded = Cache::load(&cache_path).unwrap();
+
+    // Then the newest entries are kept and survive a round trip
+    assert_eq!(loaded.len(), 3);
+    let folder = Path::new("folder");
+    assert_eq!(loaded.lookup(&key(10), hour_ago, folder), Some(1000));
+    assert_eq!(loaded.lookup(&key(11), now, folder), Some(2500));
+    assert_eq!(loaded.lookup(&key(12), hour_ago, folder), Some(3000));
+    assert_eq!(loaded.unaccounted, Some(-4096));
+    assert!(loaded.validate(home, 1).is_ok());
+    assert!(loaded.validate(Path::new("/data"), 1).is_err());
+    assert!(loaded.validate(home, 2).is_err());
+}
+
+#[test]
+fn test_cache_prune_and_invalidate() {
+    // Given sizes measured now, a month ago and at an unknown time
+    let now = SystemTime::now();
+    let month_ago = now - Duration::from_secs(30 * 24 * 60 * 60);
+    let cache = Cache::new(1, Path::new("/home"), now);
+    let paths = [
+        "",
+        "alice",
+        "alice/photos",
+        "alice/photos/2020",
+        "bob",
+        "bob/old",
+        "carol",
+    ];
+    for (file_id, path) in paths.iter().enumerate() {
+        let scanned = match *path {
+            "bob/old" => month_ago,
+            "carol" => SystemTime::UNIX_EPOCH,
+            _ => now,
+        };
+        let key = CacheKey {
+            volume_id: 1,
+            file_id: file_id as u64,
+            generation: Generation::Inode(1),
+        };
+        let size = CachedSize {
+            last_modified: month_ago,
+            disk_size: 1000,
+            path: PathBuf::from(path),
+            scanned,
+        };
+        cache.store(key, size);
+    }
+
+    // When
+    let pruned = cache.prune_older_than(Duration::from_secs(7 * 24 * 60 * 60));
+    let invalidated = cache.invalidate(Path::new("alice/photos"));
+
+    // Then old and undated sizes go, then the folder with those above and below it
+    assert_eq!(pruned, 2);
+    assert_eq!(invalidated, 4);
+    let left = cache.entries();
+    assert_eq!(left.len(), 1);
+    assert_eq!(left[0].1.path, Path::new("bob"));
+}
+
+#[test]
+fn test_cache_inode_reuse() {
+    // Given a cold folder, and a cached size left by a deleted folder which
+    // had the same file id and modification time
+    let now = SystemTime::now();
+    let month_ago = now - Duration::from_secs(30 * 24 * 60 * 60);
+    let mut fs = MemoryFs::new();
+    fs.set_time(month_ago);
+    fs.add_file("/home/alice/new/a", 1000);
+    fs.set_modified("/home", now);
+    let (file_id, last_modified, changed) = match fs.stat(Path::new("/home/alice/new"), false) {
+        Ok(FileInfo::Directory {
+            file_id,
+            last_modified,
+            changed,
+            ..
+        }) => (file_id, last_modified, changed),
+        _ => panic!("Cannot get file info"),
+    };
+    let home = Path::new("/home");
+    let stale = |generation, path: &str| {
+        let cache = Cache::new(1, home, now + Duration::from_secs(60 * 60));
+        let key = CacheKey {
+            volume_id: 1,
+            file_id,
+            generation,
+        };
+        let size = CachedSize {
+            last_modified,
+            disk_size: 9999,
+            path: PathBuf::from(path),
+            scanned: now,
+        };
+        cache.store(key, size);
+        cache
+    };
+    let config = || AnalyzeConfig {
+        root_dev: 1,
+        cache_valid_duration: Duration::from_secs(7 * 24 * 60 * 60),
+        parent_cold_duration: Duration::MAX,
+        apparent: false,
+        sort: None,
+        options: ScanOptions::default(),
+    };
+    let scan = |cache: &Cache| {
+        let result = DiskItem::with_cache_in(&fs, home, config(), 2, cache);
+        result
+            .expect("Must collect data")
+            .get(Path::new("alice/new"))
+            .unwrap()
+            .disk_size
+    };
+
+    // When the deleted folder had another change time
+    let earlier = Generation::Changed(changed - Duration::from_secs(60));
+    let reused = scan(&stale(earlier, "alice/new"));
+
+    // Then its size is not used
+    assert_eq!(reused, 1000);
+
+    // When the generation matches, but paths are verified and differ
+    let mut moved = stale(Generation::Changed(changed), "bob/old");
+    let unverified = scan(&moved);
+    moved.verify_paths = true;
+    let verified = scan(&moved);
+
+    // Then the size is used only without verification
+    assert_eq!(unverified, 9999);
+    assert_eq!(verified, 1000);
+}
+
+#[test]
+fn test_cache_file_format() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_cache_file_format/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    let hour_ago = SystemTime::now() - Duration::from_secs(60 * 60);
+    let expire = SystemTime::now() + Duration::from_secs(60 * 60);
+    let home = PathBuf::from("/home");
+    let cache_path = Path::new(DIR).join("cache.msgpack");
+    let legacy = (
+        1u64,
+        expire,
+        home.clone(),
+        vec![(10u64, (hour_ago, 1000u64))],
+    );
+    std::fs::write(&cache_path, rmp_serde::to_vec(&legacy).unwrap()).unwrap();
+
+    // When a cache without header is loaded and saved again
+    let cache = Cache::load(&cache_path).expect("Must migrate version 0");
+    cache.save(&cache_path).unwrap();
+
+    // Then it gets a header, without the entries keyed by bare file ids
+    let mut bytes = std::fs::read(&cache_path).unwrap();
+    assert!(bytes.starts_with(crate::cache::MAGIC));
+    assert_eq!(bytes[8..12], crate::cache::VERSION.to_le_bytes());
+    let cache = Cache::load(&cache_path).unwrap();
+    assert!(cache.is_empty());
+    assert!(cache.validate(&home, 1).is_ok());
+    let temp_files = std::fs::read_dir(DIR).unwrap().count();
+    assert_eq!(temp_files, 1);
+
+    // When the payload is corrupted or truncated, or the version unknown
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    std::fs::write(&cache_path, &bytes).unwrap();
+    let corrupted = Cache::load(&cache_path);
+    std::fs::write(&cache_path, &bytes[..last]).unwrap();
+    let truncated = Cache::load(&cache_path);
+    bytes[last] ^= 0xff;
+    bytes[8..12].copy_from_slice(&99u32.to_le_bytes());
+    std::fs::write(&cache_path, &bytes).unwrap();
+    let unknown = Cache::load(&cache_path);
+
+    // Then loading fails
+    assert!(corrupted.err().unwrap().to_string().contains("checksum"));
+    assert!(truncated.err().unwrap().to_string().contains("truncated"));
+    assert!(unknown.err().unwrap().to_string().contains("version 99"));
+}
+
+#[test]
+#[cfg(feature = "archives")]
+fn test_archive_members() {
+    use flate2::write::GzEncoder;
+
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_archive_members/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given
+    let file = File::create(concatcp!(DIR, "backup.tar.gz")).unwrap();
+    let mut tar = tar::Builder::new(GzEncoder::new(file, flate2::Compression::default()));
+    for (path, size) in [
+        ("docs/a.txt", 5000),
+        ("docs/b.txt", 3000),
+        ("top.bin", 10000),
+    ] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(size as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, path, &vec![0u8; size][..])
+            .unwrap();
+    }
+    tar.into_inner().unwrap().finish().unwrap();
+    let file = File::create(concatcp!(DIR, "photos.zip")).unwrap();
+    let mut zip = zip::ZipWriter::new(file);
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    zip.start_file("2023/summer.jpg", options).unwrap();
+    zip.write_all(&[0u8; 7000]).unwrap();
+    zip.finish().unwrap();
+
+    // When
+    let test_path = Path::new(DIR);
+    let result = FileInfo::from_path(test_path, false);
+
+    // Then
+    if let Result::Ok(FileInfo::Directory { volume_id, .. }) = result {
+        let options = ScanOptions {
+            look_into_archives: true,
+            ..ScanOptions::default()
+        };
+        let result = DiskItem::from_analyze_with(test_path, false, volume_id, usize::MAX, &options)
+            .expect("Must collect data");
+        let archives_size = ["backup.tar.gz", "photos.zip"]
+            .iter()
+            .map(|name| test_path.join(name).metadata().unwrap().len())
+            .sum::<u64>();
+        assert_eq!(result.disk_size, archives_size);
+        assert_eq!(result.file_count, 2);
+
+        let backup = result.get(Path::new("backup.tar.gz")).unwrap();
+        assert_eq!(backup.file_count, 1);
+        assert_eq!(backup.children.as_ref().unwrap().len(), 2);
+        let docs = result.get(Path::new("backup.tar.gz/docs")).unwrap();
+        assert_eq!(docs.disk_size, 8000);
+        assert_eq!(docs.file_count, 2);
+        let photo = result.get(Path::new("photos.zip/2023/summer.jpg")).unwrap();
+        assert_eq!(photo.disk_size, 7000);
+
+        let result = DiskItem::from_analyze(test_path, false, volume_id, usize::MAX)
+            .expect("Must collect data");
+        assert!(result
+            .get(Path::new("backup.tar.gz"))
+            .unwrap()
+            .children
+            .is_none());
+    } else {
+        panic!("Cannot get file info");
+    }
+}
+
+#[test]
+fn test_memory_fs_index() {
+    // Given
+    let day_ago = SystemTime::now() - Duration::from_secs(24 * 60 * 60);
+    let mut fs = MemoryFs::new();
+    fs.set_time(day_ago);
+    fs.add_file("/home/alice/docs/a", 1000);
+    fs.add_file("/home/alice/docs/b", 2000);
+    fs.add_file("/home/bob/c", 500);
+    let index = Arc::new(DirIndex::new());
+    let options = ScanOptions {
+        index: Some(Arc::clone(&index)),
+        ..ScanOptions::default()
+    };
+    let home = Path::new("/home");
+    let scan = |fs: &MemoryFs| {
+        DiskItem::from_analyze_in(fs, home, false, 1, usize::MAX, &options)
+            .expect("Must collect data")
+    };
+    let size = |item: &DiskItem, path: &str| item.get(Path::new(path)).unwrap().disk_size;
+    scan(&fs);
+    assert_eq!(index.len(), 4);
+
+    // When nothing changed
+    let listings = fs.listings();
+    scan(&fs);
+
+    // Then no directory is listed again
+    assert_eq!(fs.listings(), listings);
+
+    // When a file grows in place, which leaves its folder untouched
+    fs.set_time(day_ago + Duration::from_secs(60));
+    fs.set_size("/home/alice/docs/a", 5000);
+    let listings = fs.listings();
+    let result = scan(&fs);
+
+    // Then the new size is seen without listing anything
+    assert_eq!(fs.listings(), listings);
+    assert_eq!(size(&result, "alice/docs"), 5000 + 2000);
+
+    // When a file moves to another folder
+    fs.set_time(day_ago + Duration::from_secs(120));
+    fs.rename("/home/alice/docs/b", "/home/bob/b");
+    let listings = fs.listings();
+    let result = scan(&fs);
+
+    // Then both folders are listed again
+    assert_eq!(fs.listings(), listings + 2);
+    assert_eq!(size(&result, "alice/docs"), 5000);
+    assert_eq!(size(&result, "bob"), 2000 + 500);
+
+    // When a file is deleted
+    fs.set_time(day_ago + Duration::from_secs(180));
+    fs.remove("/home/bob/c");
+    let listings = fs.listings();
+    let result = scan(&fs);
+
+    // Then its folder is listed again
+    assert_eq!(fs.listings(), listings + 1);
+    assert_eq!(size(&result, "bob"), 2000);
+
+    // When a file is added and the folder mtime set back
+    fs.set_time(day_ago + Duration::from_secs(240));
+    fs.add_file("/home/alice/new", 100);
+    fs.set_modified("/home/alice", day_ago);
+    let result = scan(&fs);
+
+    // Then the change time still reveals it
+    assert_eq!(size(&result, "alice"), 5000 + 100);
+
+    // When a folder is deleted and a saved index is pruned after a scan
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_memory_fs_index/") as &str;
+    let _guard = test_dir(DIR);
+    let index_path = Path::new(DIR).join("index.msgpack");
+    index.save(&index_path).unwrap();
+    let bytes = std::fs::read(&index_path).unwrap();
+    assert!(bytes.starts_with(crate::index::MAGIC));
+    let index = Arc::new(DirIndex::load(&index_path).unwrap());
+    let options = ScanOptions {
+        index: Some(Arc::clone(&index)),
+        ..ScanOptions::default()
+    };
+    let docs_id = match fs.stat(Path::new("/home/alice/docs"), false) {
+        Ok(FileInfo::Directory { file_id, .. }) => file_id,
+        _ => panic!("Cannot get file info"),
+    };
+    fs.set_time(day_ago + Duration::from_secs(300));
+    fs.remove("/home/alice/docs");
+    let listings = fs.listings();
+    DiskItem::from_analyze_in(&fs, home, false, 1, usize::MAX, &options).unwrap();
+    index.prune();
+
+    // Then only the changed folder is listed and the deleted one is forgotten
+    assert_eq!(fs.listings(), listings + 1);
+    assert_eq!(index.len(), 3);
+    assert!(index.get(1, docs_id).is_none());
+}
+
+#[test]
+fn test_prom_exposition() {
+    // Given samples of two families, with names and labels to sanitize
+    let mut metrics = Metrics::new();
+    let help = "Sizes under \"data-2\"\n";
+    metrics
+        .family("node_data-2_folder_size_bytes", Some("bytes"), help)
+        .sample(&[("name", "a\"b\\c\nd")], 10);
+    metrics.family("scans", None, "Scans").sample(&[], 3);
+    metrics
+        .family("node_data-2_folder_size_bytes", Some("bytes"), help)
+        .sample(&[("name", "e")], 20);
+
+    // When
+    let prometheus = metrics.render(Format::Prometheus);
+    let openmetrics = metrics.render(Format::OpenMetrics);
+
+    // Then families are written once, with escaped labels, and units and
+    // an end marker in OpenMetrics only
+    assert_eq!(
+        prometheus,
+        "# HELP node_data_2_folder_size_bytes Sizes under \"data-2\"\\n\n\
+         # TYPE node_data_2_folder_size_bytes gauge\n\
+         node_data_2_folder_size_bytes{name=\"a\\\"b\\\\c\\nd\"} 10\n\
+         node_data_2_folder_size_bytes{name=\"e\"} 20\n\
+         # HELP scans Scans\n\
+         # TYPE scans gauge\n\
+         scans 3\n"
+    );
+    assert!(openmetrics
+        .contains("# HELP node_data_2_folder_size_bytes Sizes under \\\"data-2\\\"\\n\n"));
+    assert!(openmetrics.contains("# UNIT node_data_2_folder_size_bytes bytes\n"));
+    assert!(openmetrics.contains("# TYPE scans gauge\nscans 3\n"));
+    assert!(openmetrics.ends_with("# EOF\n"));
+    assert_eq!(metric_name("9 lives"), "_9_lives");
+    assert!(is_label_name("path") && !is_label_name("__name") && !is_label_name("a-b"));
+}
+
+#[test]
+fn test_http_respond() {
+    // Given metrics to serve, and a client sending a request to them
+    let mut metrics = Metrics::new();
+    metrics
+        .family("node_folder_size_bytes", Some("bytes"), "Sizes")
+        .sample(&[("name", "alice")], 10);
+    let request = |request: &str, metrics: Option<&Metrics>| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        respond(
+            &stream,
+            metrics.map(|metrics| move |format| metrics.render(format)),
+        )
+        .unwrap();
+        drop(stream);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    };
+
+    // When
+    let ok = request("GET /metrics HTTP/1.1\r\nHost: x\r\n\r\n", Some(&metrics));
+    let openmetrics = request(
+        "GET /metrics?x=1 HTTP/1.1\r\n\
+         Accept: application/openmetrics-text; version=1.0.0\r\n\r\n",
+        Some(&metrics),
+    );
+    let not_found = request("GET /other HTTP/1.1\r\n\r\n", Some(&metrics));
+    let unavailable = request("GET /metrics HTTP/1.1\r\n\r\n", None);
+    let not_allowed = request("POST /metrics HTTP/1.1\r\n\r\n", Some(&metrics));
+
+    // Then metrics are rendered in the format the client accepts
+    assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(ok.contains(&format!(
+        "Content-Type: {}\r\n",
+        Format::Prometheus.content_type()
+    )));
+    assert!(ok.ends_with(&format!("\r\n\r\n{}", metrics.render(Format::Prometheus))));
+    assert!(openmetrics.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(openmetrics.ends_with(&metrics.render(Format::OpenMetrics)));
+    assert!(not_found.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    assert!(unavailable.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+    assert!(not_allowed.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+}
+
+#[test]
+fn test_textfile_kept_on_failure() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_textfile_kept_on_failure/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given the textfile of a successful scan
+    let path = Path::new(DIR).join("home_sizes.prom");
+    let read = |path: &Path| std::fs::read_to_string(path).unwrap();
+    write_textfile(
+        &path,
+        Some("node_folder_size_bytes 10\n"),
+        Format::Prometheus,
+    )
+    .unwrap();
+    assert_eq!(read(&path), "node_folder_size_bytes 10\n");
+    assert_eq!(
+        status_path(&path),
+        Path::new(DIR).join("home_sizes.status.prom")
+    );
+    let status = read(&status_path(&path));
+    assert!(status.contains("home_sizes_prom_scan_failed{textfile=\"home_sizes.prom\"} 0\n"));
+
+    // When the next scan fails
+    write_textfile(&path, None, Format::Prometheus).unwrap();
+
+    // Then the textfile is kept, and the status tells the failure
+    assert_eq!(read(&path), "node_folder_size_bytes 10\n");
+    let status = read(&status_path(&path));
+    assert!(status.contains("home_sizes_prom_scan_failed{textfile=\"home_sizes.prom\"} 1\n"));
+    // Without temporary files left over
+    assert_eq!(std::fs::read_dir(DIR).unwrap().count(), 2);
+}
+
+#[test]
+fn test_quota_limits_file() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_quota_limits_file/") as &str;
+    let _guard = test_dir(DIR);
+
+    // Given a limits file with a size in bytes, one with a unit, and a
+    // directory listed through a relative path
+    create_dir(concatcp!(DIR, "alice") as &str);
+    create_dir(concatcp!(DIR, "bob") as &str);
+    create_dir(concatcp!(DIR, "carol") as &str);
+    let limits_path = concatcp!(DIR, "limits.toml") as &str;
+    let mut file = File::create(limits_path).unwrap();
+    writeln!(
+        file,
+        "[limits]\n\
+         \"{dir}alice\" = 1000\n\
+         \"{dir}bob/../bob\" = \"1.5 GiB\"",
+        dir = DIR
+    )
+    .unwrap();
+
+    // When
+    let source: QuotaSource = limits_path.parse().unwrap();
+    let limits = Limits::new(source).unwrap();
+
+    // Then listed directories get their limit, whatever the path to them
+    let limit = |dir: &str| limits.limit(Path::new(dir)).unwrap();
+    assert_eq!(limit(concatcp!(DIR, "alice") as &str), Some(1000));
+    assert_eq!(limit(concatcp!(DIR, "bob") as &str), Some(3 << 29));
+    assert_eq!(
+        limit(concatcp!(DIR, "./carol/../alice") as &str),
+        Some(1000)
+    );
+    assert_eq!(limit(concatcp!(DIR, "carol") as &str), None);
+    assert_eq!(parse_size("50G"), Some(50 << 30));
+    assert_eq!(parse_size("2kB"), Some(2048));
+    assert_eq!(parse_size("ten"), None);
+    assert_eq!("user".parse(), Ok(QuotaSource::User));
+
+    // Then files have no quota, without reading any
+    if cfg!(target_os = "linux") {
+        let quotas = Limits::new(QuotaSource::User).unwrap();
+        assert_eq!(quotas.limit(Path::new(limits_path)).unwrap(), None);
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_quota_errors_skip_children() {
+    // Given a scan finding a file, and a folder removed since
+    let mut fs = MemoryFs::new();
+    fs.add_file("/crate/Cargo.toml", 100);
+    fs.add_dir("/crate/gone");
+    let options = ScanOptions::default();
+    let item = DiskItem::from_analyze_in(&fs, Path::new("/crate"), false, 1, usize::MAX, &options)
+        .expect("Must collect data");
+
+    // When the quotas of its children are read from the crate directory
+    let limits = Limits::new(QuotaSource::User).unwrap();
+    let children = limits.children(Path::new("."), &item);
+
+    // Then the removed folder alone is skipped, and the file has no quota
+    let children = children.expect("Must skip the removed folder");
+    assert!(children.limits.is_empty());
+    assert_eq!(children.skipped.len(), 1);
+    let (child, error) = &children.skipped[0];
+    assert_eq!(child.name, "gone");
+    assert!(!error.is_unavailable());
+}
+
+#[test]
+fn test_targets_file() {
+    const DIR: &str = concatcp!(TEST_DATA_DIR, "test_targets_file/") as &str;
+    let _guard = test_dir(DIR);
+    let load = |name: &str, toml: &str| {
+        let path = Path::new(DIR).join(name);
+        std::fs::write(&path, toml).unwrap();
+        targets::load(&path)
+    };
+
+    // Given a target with defaults and one overriding them, with labels
+    let toml = "[[target]]\n\
+                path = \"/home\"\n\
+                [[target]]\n\
+                path = \"/data\"\n\
+                depth = 3\n\
+                valid_days = 30\n\
+                cache = \"/var/lib/data.msgpack\"\n\
+                drift_threshold = 1.5\n\
+                drift_invalidation = \"subtree\"\n\
+                labels = { volume = \"data\", team = \"ml\" }\n";
+
+    // When
+    let targets = load("targets.toml", toml).unwrap();
+
+    // Then options left out take the defaults of the command line
+    assert_eq!(targets.len(), 2);
+    let (home, data) = (&targets[0], &targets[1]);
+    assert_eq!(home.path, Path::new("/home"));
+    assert_eq!(
+        (home.depth, home.valid_days, home.parent_valid_days),
+        (2, 7, 365)
+    );
+    assert_eq!(home.expire_hours, 24);
+    assert_eq!(home.cache, None);
+    assert_eq!(home.drift_invalidation, DriftInvalidation::Full);
+    assert!(home.labels.is_empty());
+    assert_eq!((data.depth, data.valid_days), (3, 30));
+    assert_eq!(data.cache, Some(PathBuf::from("/var/lib/data.msgpack")));
+    assert_eq!(data.drift_threshold, Some(1.5));
+    assert_eq!(data.drift_invalidation, DriftInvalidation::Subtree);
+    assert_eq!(data.labels["volume"], "data");
+    assert_eq!(data.labels["team"], "ml");
+
+    // Then unknown keys, invalid and reserved label names are rejected
+    let unknown = "[[target]]\npath = \"/home\"\ndepht = 3\n";
+    assert!(load("unknown.toml", unknown).is_err());
+    let invalid = "[[target]]\npath = \"/home\"\nlabels = { \"a-b\" = \"x\" }\n";
+    assert!(load("invalid.toml", invalid).is_err());
+    for name in targets::RESERVED_LABELS {
+        let reserved = format!(
+            "[[target]]\npath = \"/home\"\nlabels = {{ {} = \"x\" }}\n",
+            name
+        );
+        let error = load("reserved.toml", &reserved).unwrap_err();
+        assert!(error.to_string().contains("reserved"), "{}", error);
+    }
+}
+
 // Helper functions and cleanup code goes next
 
 fn create_dir(dir_path: &str) {
@@ -129,6 +1472,15 @@ fn create_file(file_path: &str, size: usize) {
     file.write(&content).unwrap();
 }
 
+/// Creates the folder `path` of a test, empty, and removes it once the guard
+/// returned is dropped.
+#[must_use = "binding the guard to `_` would remove the folder right away"]
+fn test_dir(path: &'static str) -> CleanUpGuard {
+    let _ = std::fs::remove_dir_all(path);
+    create_dir(path);
+    CleanUpGuard { path }
+}
+
 /// Used to clean up test folder after test runs.
 struct CleanUpGuard {
     path: &'static str,
@@ -0,0 +1,47 @@
+//! Space used on the volume holding a path, as `df` reports it.
+
+use std::io;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VolumeUsage {
+    /// Size of the volume in bytes.
+    pub total: u64,
+    /// Bytes in use, including those reserved for the superuser.
+    pub used: u64,
+}
+
+/// Usage of the volume holding `path`, from `statvfs`.
+#[cfg(unix)]
+pub fn volume_usage(path: &Path) -> io::Result<VolumeUsage> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    if unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let stat = unsafe { stat.assume_init() };
+    // Field widths differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    let (blocks, free, fragment) = (
+        stat.f_blocks as u64,
+        stat.f_bfree as u64,
+        stat.f_frsize as u64,
+    );
+    Ok(VolumeUsage {
+        total: blocks * fragment,
+        used: (blocks - free) * fragment,
+    })
+}
+
+/// Usage of the volume holding `path`, from `GetDiskFreeSpaceExW`.
+#[cfg(windows)]
+pub fn volume_usage(path: &Path) -> io::Result<VolumeUsage> {
+    let (total, free) = crate::ffi::disk_space(path)?;
+    Ok(VolumeUsage {
+        total,
+        used: total - free,
+    })
+}